@@ -0,0 +1,38 @@
+use std::fmt;
+use std::error::Error;
+
+// Error found while assembling, located by its line and column (both starting at 1).
+#[derive(Debug)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl AsmError {
+    pub fn new<S>(line: usize, column: usize, message: S) -> AsmError
+        where S: Into<String>
+    {
+        AsmError {
+            line,
+            column,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for AsmError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        None
+    }
+}
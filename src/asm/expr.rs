@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+
+use super::AsmError;
+
+// What an expression can refer to: the symbols (labels and constants) and the address of the
+// current statement, written `$`.
+pub struct Context<'a> {
+    pub symbols: &'a HashMap<String, i64>,
+    pub address: usize,
+    pub line: usize,
+}
+
+// Evaluate the expression found at the given column of the current line. The operators are the
+// C ones, with the same precedence: unary - ~ +, then * / %, + -, << >>, &, ^ and |.
+pub fn evaluate(text: &str, column: usize, context: &Context) -> Result<i64, AsmError> {
+    let mut parser = Parser {
+        text: text.as_bytes(),
+        pos: 0,
+        column,
+        context,
+    };
+    let value = parser.binary(0)?;
+    parser.skip_whitespace();
+    if parser.pos < parser.text.len() {
+        return Err(parser.error("unexpected character"));
+    }
+    Ok(value)
+}
+
+pub fn is_identifier_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_' || c == b'.'
+}
+
+pub fn is_identifier(c: u8) -> bool {
+    is_identifier_start(c) || c.is_ascii_digit()
+}
+
+// Binary operators from the lowest to the highest precedence.
+const OPERATORS: [&[&str]; 6] = [&["|"],
+                                 &["^"],
+                                 &["&"],
+                                 &["<<", ">>"],
+                                 &["+", "-"],
+                                 &["*", "/", "%"]];
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+    column: usize,
+    context: &'a Context<'a>,
+}
+
+impl<'a> Parser<'a> {
+    fn error<S>(&self, message: S) -> AsmError
+        where S: Into<String>
+    {
+        AsmError::new(self.context.line, self.column + self.pos, message)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.text.len() && self.text[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn operator(&mut self, level: usize) -> Option<&'static str> {
+        self.skip_whitespace();
+        let rest = &self.text[self.pos..];
+        let found = OPERATORS[level].iter().find(|op| rest.starts_with(op.as_bytes())).cloned();
+        if let Some(op) = found {
+            self.pos += op.len();
+        }
+        found
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, AsmError> {
+        if level == OPERATORS.len() {
+            return self.unary();
+        }
+
+        let mut value = self.binary(level + 1)?;
+        loop {
+            self.skip_whitespace();
+            let start = self.pos;
+            let op = match self.operator(level) {
+                Some(op) => op,
+                None => return Ok(value),
+            };
+            let rhs = self.binary(level + 1)?;
+            value = match op {
+                "|" => value | rhs,
+                "^" => value ^ rhs,
+                "&" => value & rhs,
+                "<<" | ">>" => {
+                    if !(0..64).contains(&rhs) {
+                        self.pos = start;
+                        return Err(self.error("shift amount out of range"));
+                    }
+                    if op == "<<" { value << rhs } else { value >> rhs }
+                }
+                "+" => value.wrapping_add(rhs),
+                "-" => value.wrapping_sub(rhs),
+                "*" => value.wrapping_mul(rhs),
+                _ => {
+                    if rhs == 0 {
+                        self.pos = start;
+                        return Err(self.error("division by zero"));
+                    }
+                    if op == "/" { value.wrapping_div(rhs) } else { value.wrapping_rem(rhs) }
+                }
+            };
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64, AsmError> {
+        self.skip_whitespace();
+        match self.text.get(self.pos) {
+            Some(&b'-') => {
+                self.pos += 1;
+                Ok(self.unary()?.wrapping_neg())
+            }
+            Some(&b'~') => {
+                self.pos += 1;
+                Ok(!self.unary()?)
+            }
+            Some(&b'+') => {
+                self.pos += 1;
+                self.unary()
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<i64, AsmError> {
+        let start = self.pos;
+        match self.text.get(self.pos).cloned() {
+            Some(b'(') => {
+                self.pos += 1;
+                let value = self.binary(0)?;
+                self.skip_whitespace();
+                if self.text.get(self.pos) != Some(&b')') {
+                    return Err(self.error("expected a closing parenthesis"));
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            Some(b'$') => {
+                self.pos += 1;
+                Ok(self.context.address as i64)
+            }
+            Some(c) if c.is_ascii_digit() => {
+                while self.pos < self.text.len() && is_identifier(self.text[self.pos]) {
+                    self.pos += 1;
+                }
+                let literal = String::from_utf8_lossy(&self.text[start..self.pos]).into_owned();
+                parse_number(&literal).ok_or_else(|| {
+                    self.pos = start;
+                    self.error(format!("invalid number {}", literal))
+                })
+            }
+            Some(c) if is_identifier_start(c) => {
+                while self.pos < self.text.len() && is_identifier(self.text[self.pos]) {
+                    self.pos += 1;
+                }
+                let name = String::from_utf8_lossy(&self.text[start..self.pos]).into_owned();
+                match self.context.symbols.get(&name) {
+                    Some(value) => Ok(*value),
+                    None => {
+                        self.pos = start;
+                        Err(self.error(format!("undefined symbol {}", name)))
+                    }
+                }
+            }
+            Some(_) => Err(self.error("expected a number or a symbol")),
+            None => Err(self.error("missing value")),
+        }
+    }
+}
+
+// Decimal, hexadecimal (0x) or binary (0b) number.
+pub fn parse_number(s: &str) -> Option<i64> {
+    let lower = s.to_lowercase();
+    let result = if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2)
+    } else {
+        lower.parse()
+    };
+    result.ok()
+}
@@ -0,0 +1,589 @@
+// Assembler for the syntax printed by the disassembler (the `Display` of the instructions),
+// plus labels, constants, expressions and a few directives:
+//
+//     ; comments start with a semicolon
+//     SPEED   EQU 3                ; constant, defined before it is used
+//     start:  LD V0, SPEED * 2     ; label, instruction
+//             JP start
+//             ORG 0x300            ; continue assembling at this address
+//     sprite: DB 0xF0, 0x90, 0xF0  ; bytes
+//             DW 0x1234, sprite    ; big-endian dwords
+//
+// Mnemonics, registers and directives are case insensitive, symbols are not. The address
+// prefix of the disassembler listing (`0x200\t| `) is ignored, so its output assembles back to
+// the same ROM.
+
+mod error;
+mod expr;
+
+use std::collections::HashMap;
+
+use cpu::inst::{Instruction, Value};
+use memory::XO_RAM_SIZE;
+use self::expr::Context;
+
+pub use self::error::AsmError;
+
+// Address of the first byte of the ROM.
+const ORIGIN: usize = 0x200;
+
+// Instructions and data directives.
+const MNEMONICS: [&str; 34] = ["CLS", "RET", "SCR", "SCL", "EXIT", "LOW", "HIGH", "AUDIO", "SYS",
+                               "SCD", "SCU", "PLANE", "JP", "CALL", "SE", "SNE", "SAVE", "LOAD",
+                               "ADD", "OR", "AND", "XOR", "SUB", "SUBN", "SHR", "SHL", "RND",
+                               "DRW", "SKP", "SKNP", "PITCH", "LD", "DB", "DW"];
+
+const RESERVED: [&str; 11] = ["I", "[I]", "DT", "ST", "K", "F", "HF", "B", "R", "LONG", "EQU"];
+
+// Part of a line along with the column it starts at.
+#[derive(Clone,Copy)]
+struct Field<'a> {
+    text: &'a str,
+    column: usize,
+}
+
+#[derive(Clone,Copy)]
+enum Operand<'a> {
+    Register(u8),
+    I,
+    IndirectI,
+    DT,
+    ST,
+    K,
+    F,
+    HF,
+    B,
+    R,
+    Long(Field<'a>),
+    Expression(Field<'a>),
+}
+
+struct Line<'a> {
+    label: Option<Field<'a>>,
+    mnemonic: Option<Field<'a>>,
+    operands: Vec<Field<'a>>,
+}
+
+struct Statement<'a> {
+    line: usize,
+    address: usize,
+    mnemonic: Field<'a>,
+    operands: Vec<Field<'a>>,
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    // Columns are byte offsets while assembling, the errors count characters
+    assemble_lines(source).map_err(|mut err| {
+        if let Some(text) = source.lines().nth(err.line - 1) {
+            let offset = err.column - 1;
+            err.column = text.char_indices().take_while(|&(pos, _)| pos < offset).count() + 1;
+        }
+        err
+    })
+}
+
+fn assemble_lines(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut symbols: HashMap<String, i64> = HashMap::new();
+    let mut statements = Vec::new();
+    let mut address = ORIGIN;
+
+    // First pass: find the address of every statement and label
+    for (idx, text) in source.lines().enumerate() {
+        let number = idx + 1;
+        let line = split_line(text, number)?;
+
+        let mnemonic = match line.mnemonic {
+            Some(mnemonic) => mnemonic,
+            None => {
+                if let Some(label) = line.label {
+                    define(&mut symbols, label, address as i64, number)?;
+                }
+                continue;
+            }
+        };
+        let upper = mnemonic.text.to_uppercase();
+        if let Some(label) = line.label {
+            if upper != "EQU" {
+                define(&mut symbols, label, address as i64, number)?;
+            }
+        }
+
+        let context = Context {
+            symbols: &symbols,
+            address,
+            line: number,
+        };
+        match &upper[..] {
+            "ORG" => {
+                let operand = single_operand(&mnemonic, &line.operands, number)?;
+                let value = expr::evaluate(operand.text, operand.column, &context)?;
+                if value < ORIGIN as i64 || value >= XO_RAM_SIZE as i64 {
+                    return Err(AsmError::new(number,
+                                             operand.column,
+                                             format!("origin {:#X} is out of the program \
+                                                      memory",
+                                                     value)));
+                }
+                address = value as usize;
+            }
+            "EQU" => {
+                let name = match line.label {
+                    Some(label) => label,
+                    None => {
+                        return Err(AsmError::new(number, mnemonic.column, "EQU needs a name"))
+                    }
+                };
+                let operand = single_operand(&mnemonic, &line.operands, number)?;
+                let value = expr::evaluate(operand.text, operand.column, &context)?;
+                define(&mut symbols, name, value, number)?;
+            }
+            upper => {
+                if !MNEMONICS.contains(&upper) {
+                    return Err(AsmError::new(number,
+                                             mnemonic.column,
+                                             format!("unknown instruction {}", mnemonic.text)));
+                }
+                let size = match upper {
+                    "DB" => line.operands.len(),
+                    "DW" => line.operands.len() * 2,
+                    _ => {
+                        let long = line.operands
+                            .iter()
+                            .any(|operand| starts_with_keyword(operand.text, "LONG"));
+                        if long { 4 } else { 2 }
+                    }
+                };
+                if address + size > XO_RAM_SIZE {
+                    return Err(AsmError::new(number, mnemonic.column, "program too large"));
+                }
+                statements.push(Statement {
+                                    line: number,
+                                    address,
+                                    mnemonic,
+                                    operands: line.operands,
+                                });
+                address += size;
+            }
+        }
+    }
+
+    // Second pass: now that every symbol is known, encode the statements
+    let mut rom = Vec::new();
+    for statement in statements.iter() {
+        let context = Context {
+            symbols: &symbols,
+            address: statement.address,
+            line: statement.line,
+        };
+        let bytes = encode_statement(statement, &context)?;
+        let start = statement.address - ORIGIN;
+        if rom.len() < start + bytes.len() {
+            rom.resize(start + bytes.len(), 0u8);
+        }
+        rom[start..start + bytes.len()].copy_from_slice(&bytes);
+    }
+    Ok(rom)
+}
+
+// Split a line into its label, mnemonic and operands, skipping the comments and the address
+// printed by the disassembler.
+fn split_line<'a>(text: &'a str, number: usize) -> Result<Line<'a>, AsmError> {
+    let code = match text.find(';') {
+        Some(end) => &text[..end],
+        None => text,
+    };
+    let mut pos = match code.find('|') {
+        Some(end) if expr::parse_number(code[..end].trim()).is_some() => end + 1,
+        _ => 0,
+    };
+
+    let mut line = Line {
+        label: None,
+        mnemonic: None,
+        operands: Vec::new(),
+    };
+    let mut word = next_word(code, &mut pos);
+
+    // A label is a symbol followed by a colon, a constant name is followed by EQU
+    if let Some(first) = word {
+        let label = if first.text.ends_with(':') {
+            Some(Field {
+                     text: &first.text[..first.text.len() - 1],
+                     column: first.column,
+                 })
+        } else {
+            let mut lookahead = pos;
+            match next_word(code, &mut lookahead) {
+                Some(second) if second.text.eq_ignore_ascii_case("EQU") => Some(first),
+                _ => None,
+            }
+        };
+        if let Some(label) = label {
+            check_symbol(label, number)?;
+            line.label = Some(label);
+            word = next_word(code, &mut pos);
+        }
+    }
+
+    line.mnemonic = word;
+    let rest = &code[pos..];
+    if rest.trim().is_empty() {
+        return Ok(line);
+    }
+    let mut start = pos;
+    for operand in rest.split(',') {
+        let trimmed = operand.trim();
+        let column = start + (operand.len() - operand.trim_start().len()) + 1;
+        if trimmed.is_empty() {
+            return Err(AsmError::new(number, column, "missing operand"));
+        }
+        line.operands.push(Field {
+                               text: trimmed,
+                               column,
+                           });
+        start += operand.len() + 1;
+    }
+    Ok(line)
+}
+
+fn next_word<'a>(text: &'a str, pos: &mut usize) -> Option<Field<'a>> {
+    let rest = &text[*pos..];
+    let start = *pos + (rest.len() - rest.trim_start().len());
+    let len = text[start..].find(char::is_whitespace).unwrap_or(text.len() - start);
+    if len == 0 {
+        return None;
+    }
+    *pos = start + len;
+    Some(Field {
+             text: &text[start..start + len],
+             column: start + 1,
+         })
+}
+
+fn check_symbol(symbol: Field, number: usize) -> Result<(), AsmError> {
+    let bytes = symbol.text.as_bytes();
+    let valid = !bytes.is_empty() && expr::is_identifier_start(bytes[0]) &&
+                bytes.iter().all(|c| expr::is_identifier(*c));
+    if !valid {
+        return Err(AsmError::new(number,
+                                 symbol.column,
+                                 format!("invalid symbol name {}", symbol.text)));
+    }
+    if register(symbol.text).is_some() ||
+       RESERVED.iter().any(|word| symbol.text.eq_ignore_ascii_case(word)) {
+        return Err(AsmError::new(number,
+                                 symbol.column,
+                                 format!("{} is a reserved word", symbol.text)));
+    }
+    Ok(())
+}
+
+fn define(symbols: &mut HashMap<String, i64>,
+          symbol: Field,
+          value: i64,
+          number: usize)
+          -> Result<(), AsmError> {
+    if symbols.contains_key(symbol.text) {
+        return Err(AsmError::new(number,
+                                 symbol.column,
+                                 format!("{} is already defined", symbol.text)));
+    }
+    symbols.insert(String::from(symbol.text), value);
+    Ok(())
+}
+
+fn single_operand<'a>(mnemonic: &Field,
+                      operands: &[Field<'a>],
+                      number: usize)
+                      -> Result<Field<'a>, AsmError> {
+    match operands.len() {
+        1 => Ok(operands[0]),
+        _ => {
+            Err(AsmError::new(number,
+                              mnemonic.column,
+                              format!("{} takes exactly one operand", mnemonic.text)))
+        }
+    }
+}
+
+fn starts_with_keyword(text: &str, keyword: &str) -> bool {
+    text.len() > keyword.len() && text[..keyword.len()].eq_ignore_ascii_case(keyword) &&
+    text[keyword.len()..].starts_with(char::is_whitespace)
+}
+
+// Registers are written in hexadecimal (VA) or decimal (V10) as the disassembler does both.
+fn register(text: &str) -> Option<u8> {
+    if text.len() < 2 || !(text.starts_with('V') || text.starts_with('v')) {
+        return None;
+    }
+    let digits = &text[1..];
+    match digits.len() {
+        1 => u8::from_str_radix(digits, 16).ok(),
+        2 => digits.parse().ok().filter(|reg| *reg >= 10 && *reg < 16),
+        _ => None,
+    }
+}
+
+fn classify(field: Field) -> Operand {
+    if let Some(reg) = register(field.text) {
+        return Operand::Register(reg);
+    }
+    if starts_with_keyword(field.text, "LONG") {
+        let rest = &field.text[4..];
+        let expression = rest.trim_start();
+        return Operand::Long(Field {
+                                 text: expression,
+                                 column: field.column + 4 + rest.len() - expression.len(),
+                             });
+    }
+    match &field.text.to_uppercase()[..] {
+        "I" => Operand::I,
+        "[I]" => Operand::IndirectI,
+        "DT" => Operand::DT,
+        "ST" => Operand::ST,
+        "K" => Operand::K,
+        "F" => Operand::F,
+        "HF" => Operand::HF,
+        "B" => Operand::B,
+        "R" => Operand::R,
+        _ => Operand::Expression(field),
+    }
+}
+
+// Evaluate an expression, checking that it fits in the given range.
+fn value(field: Field, context: &Context, min: i64, max: i64, kind: &str) -> Result<i64, AsmError> {
+    let value = expr::evaluate(field.text, field.column, context)?;
+    if value < min || value > max {
+        return Err(AsmError::new(context.line,
+                                 field.column,
+                                 format!("{} doesn't fit in {}", value, kind)));
+    }
+    Ok(value)
+}
+
+fn address(field: Field, context: &Context) -> Result<u16, AsmError> {
+    value(field, context, 0, 0xFFF, "an address").map(|value| value as u16)
+}
+
+// Bytes may also be written as negative numbers.
+fn byte(field: Field, context: &Context) -> Result<u8, AsmError> {
+    value(field, context, -0x80, 0xFF, "a byte").map(|value| value as u8)
+}
+
+fn nibble(field: Field, context: &Context) -> Result<u8, AsmError> {
+    value(field, context, 0, 0xF, "a nibble").map(|value| value as u8)
+}
+
+fn encode_statement(statement: &Statement, context: &Context) -> Result<Vec<u8>, AsmError> {
+    let upper = statement.mnemonic.text.to_uppercase();
+    match &upper[..] {
+        "DB" => {
+            return statement.operands
+                .iter()
+                .map(|operand| byte(*operand, context))
+                .collect()
+        }
+        "DW" => {
+            let mut bytes = Vec::new();
+            for operand in statement.operands.iter() {
+                let dword = value(*operand, context, -0x8000, 0xFFFF, "a dword")? as u16;
+                bytes.push((dword >> 8) as u8);
+                bytes.push(dword as u8);
+            }
+            return Ok(bytes);
+        }
+        _ => (),
+    }
+
+    let operands: Vec<Operand> = statement.operands.iter().map(|field| classify(*field)).collect();
+    let inst = parse_instruction(&upper, &operands, context)?
+        .ok_or_else(|| {
+            AsmError::new(statement.line,
+                          statement.mnemonic.column,
+                          format!("invalid operands for {}", statement.mnemonic.text))
+        })?;
+    let mut dwords = vec![inst.to_binary()];
+    dwords.extend(inst.long_operand());
+    Ok(dwords.iter().flat_map(|dword| vec![(dword >> 8) as u8, *dword as u8]).collect())
+}
+
+// Build the instruction from its mnemonic and operands, None if they don't match any.
+fn parse_instruction(mnemonic: &str,
+                     operands: &[Operand],
+                     context: &Context)
+                     -> Result<Option<Instruction>, AsmError> {
+    use self::Instruction::*;
+    use self::Operand::{Register, Long, Expression};
+
+    let inst = match (mnemonic, operands) {
+        ("CLS", &[]) => CLS,
+        ("RET", &[]) => RET,
+        ("SCR", &[]) => SCR,
+        ("SCL", &[]) => SCL,
+        ("EXIT", &[]) => EXIT,
+        ("LOW", &[]) => LOW,
+        ("HIGH", &[]) => HIGH,
+        ("AUDIO", &[]) => AUDIO,
+        ("SYS", &[Expression(addr)]) => SYS(address(addr, context)?),
+        ("SCD", &[Expression(n)]) => SCD(nibble(n, context)?),
+        ("SCU", &[Expression(n)]) => SCU(nibble(n, context)?),
+        ("PLANE", &[Expression(n)]) => PLANE(nibble(n, context)?),
+        ("JP", &[Expression(addr)]) => JP(address(addr, context)?),
+        ("JP", &[Register(0), Expression(addr)]) => JPO(address(addr, context)?),
+        ("CALL", &[Expression(addr)]) => CALL(address(addr, context)?),
+        ("SE", &[Register(x), Register(y)]) => SE(x, Value::Register(y)),
+        ("SE", &[Register(x), Expression(b)]) => SE(x, Value::Byte(byte(b, context)?)),
+        ("SNE", &[Register(x), Register(y)]) => SNE(x, Value::Register(y)),
+        ("SNE", &[Register(x), Expression(b)]) => SNE(x, Value::Byte(byte(b, context)?)),
+        ("SAVE", &[Register(x), Register(y)]) => SAVE(x, y),
+        ("LOAD", &[Register(x), Register(y)]) => LOAD(x, y),
+        ("ADD", &[Register(x), Register(y)]) => ADD(x, Value::Register(y)),
+        ("ADD", &[Register(x), Expression(b)]) => ADD(x, Value::Byte(byte(b, context)?)),
+        ("ADD", &[Operand::I, Register(x)]) => ADDI(x),
+        ("OR", &[Register(x), Register(y)]) => OR(x, y),
+        ("AND", &[Register(x), Register(y)]) => AND(x, y),
+        ("XOR", &[Register(x), Register(y)]) => XOR(x, y),
+        ("SUB", &[Register(x), Register(y)]) => SUB(x, y),
+        ("SUBN", &[Register(x), Register(y)]) => SUBN(x, y),
+        ("SHR", &[Register(x)]) => SHR(x, x),
+        ("SHR", &[Register(x), Register(y)]) => SHR(x, y),
+        ("SHL", &[Register(x)]) => SHL(x, x),
+        ("SHL", &[Register(x), Register(y)]) => SHL(x, y),
+        ("RND", &[Register(x), Expression(b)]) => RND(x, byte(b, context)?),
+        ("DRW", &[Register(x), Register(y), Expression(n)]) => DRW(x, y, nibble(n, context)?),
+        ("SKP", &[Register(x)]) => SKP(x),
+        ("SKNP", &[Register(x)]) => SKNP(x),
+        ("PITCH", &[Register(x)]) => PITCH(x),
+        ("LD", &[Register(x), Register(y)]) => LD(x, Value::Register(y)),
+        ("LD", &[Register(x), Expression(b)]) => LD(x, Value::Byte(byte(b, context)?)),
+        ("LD", &[Operand::I, Expression(addr)]) => LDI(address(addr, context)?),
+        ("LD", &[Operand::I, Long(addr)]) => {
+            LDIL(value(addr, context, 0, 0xFFFF, "a long address")? as u16)
+        }
+        ("LD", &[Register(x), Operand::DT]) => LDDT(x),
+        ("LD", &[Register(x), Operand::K]) => LDK(x),
+        ("LD", &[Operand::DT, Register(x)]) => LDSDT(x),
+        ("LD", &[Operand::ST, Register(x)]) => LDSST(x),
+        ("LD", &[Operand::F, Register(x)]) => LDF(x),
+        ("LD", &[Operand::HF, Register(x)]) => LDHF(x),
+        ("LD", &[Operand::B, Register(x)]) => LDB(x),
+        ("LD", &[Operand::IndirectI, Register(x)]) => LDSBLK(x),
+        ("LD", &[Register(x), Operand::IndirectI]) => LDBLK(x),
+        ("LD", &[Operand::R, Register(x)]) => LDSR(x),
+        ("LD", &[Register(x), Operand::R]) => LDR(x),
+        _ => return Ok(None),
+    };
+    Ok(Some(inst))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::assemble;
+    use disasm::{Linear, Program};
+
+    // Code reaching data, sprites, a long load, a JP V0 and a jump in the middle of an
+    // instruction, with an odd size
+    const ROM: [u8; 31] = [0x22, 0x0A, 0xA2, 0x18, 0xD0, 0x15, 0xB2, 0x10, 0x12, 0x03, 0xF0,
+                           0x00, 0x02, 0x18, 0x00, 0xEE, 0x12, 0x00, 0xFF, 0xFF, 0xF0, 0x90,
+                           0xF0, 0x90, 0xF0, 0x5A, 0x81, 0x23, 0x00, 0x01, 0x07];
+
+    // Deterministic pseudo-random ROMs of various sizes
+    fn roms() -> Vec<Vec<u8>> {
+        let mut state = 0x2545F491u32;
+        let mut roms = vec![ROM.to_vec()];
+        for size in 1..64 {
+            roms.push((0..size * 3)
+                          .map(|_| {
+                              state ^= state << 13;
+                              state ^= state >> 17;
+                              state ^= state << 5;
+                              (state >> 24) as u8
+                          })
+                          .collect());
+        }
+        roms
+    }
+
+    fn error(source: &str) -> (usize, usize, String) {
+        let err = assemble(source).unwrap_err();
+        (err.line, err.column, err.message)
+    }
+
+    #[test]
+    fn flow_round_trip() {
+        for rom in roms() {
+            let listing = Program::analyze(&rom).to_string();
+            assert_eq!(assemble(&listing).unwrap(), rom, "{}", listing);
+        }
+    }
+
+    #[test]
+    fn linear_round_trip() {
+        for rom in roms() {
+            let listing = Linear::new(&rom).to_string();
+            assert_eq!(assemble(&listing).unwrap(), rom, "{}", listing);
+        }
+    }
+
+    #[test]
+    fn labels() {
+        let source = "start:  JP end\n\
+                      loop:\n\
+                              CALL loop\n\
+                      end:    JP start";
+        assert_eq!(assemble(source).unwrap(), [0x12, 0x04, 0x22, 0x02, 0x12, 0x00]);
+        assert_eq!(error("a: CLS\na: RET"), (2, 1, String::from("a is already defined")));
+        assert_eq!(error("VA: CLS"), (1, 1, String::from("VA is a reserved word")));
+    }
+
+    #[test]
+    fn db() {
+        assert_eq!(assemble("DB 0xF0, -1, 0b101, 7").unwrap(), [0xF0, 0xFF, 0x05, 0x07]);
+        assert_eq!(error("DB 1, 256"), (1, 7, String::from("256 doesn't fit in a byte")));
+        assert_eq!(error("DB 1,, 2"), (1, 6, String::from("missing operand")));
+    }
+
+    #[test]
+    fn dw() {
+        assert_eq!(assemble("CLS\nDW 0x1234, $, -2").unwrap(),
+                   [0x00, 0xE0, 0x12, 0x34, 0x02, 0x02, 0xFF, 0xFE]);
+        assert_eq!(error("DW 0x10000"), (1, 4, String::from("65536 doesn't fit in a dword")));
+    }
+
+    #[test]
+    fn org() {
+        let source = "CLS\n\
+                      ORG 0x208\n\
+                      here: JP here";
+        assert_eq!(assemble(source).unwrap(), [0x00, 0xE0, 0, 0, 0, 0, 0, 0, 0x12, 0x08]);
+        assert_eq!(error("ORG 0x100"),
+                   (1, 5, String::from("origin 0x100 is out of the program memory")));
+        assert_eq!(error("ORG 1, 2"), (1, 1, String::from("ORG takes exactly one operand")));
+    }
+
+    #[test]
+    fn constants_and_expressions() {
+        let source = "SPEED EQU 3\n\
+                      MASK  equ ~0 & 0xF0\n\
+                      LD V0, SPEED * 2 + (1 << 2)\n\
+                      LD V1, MASK | SPEED % 2\n\
+                      LD I, sprite - 1\n\
+                      sprite: DB (sprite - $) ^ 5, -SPEED";
+        assert_eq!(assemble(source).unwrap(),
+                   [0x60, 0x0A, 0x61, 0xF1, 0xA2, 0x05, 0x05, 0xFD]);
+        assert_eq!(error("LD V0, 1 / (2 - 2)"), (1, 10, String::from("division by zero")));
+        assert_eq!(error("ORG LATER\nLATER EQU 0x300"),
+                   (1, 5, String::from("undefined symbol LATER")));
+    }
+
+    #[test]
+    fn error_position() {
+        assert_eq!(error("CLS\n  LD V1, 1 + missing"),
+                   (2, 14, String::from("undefined symbol missing")));
+        assert_eq!(error("CLS ; done\n\tFOO V1"), (2, 2, String::from("unknown instruction FOO")));
+        // Columns count characters, not bytes
+        assert_eq!(error("\u{A0}\u{A0}LD V0, missing ; é"),
+                   (1, 10, String::from("undefined symbol missing")));
+        assert_eq!(error("LD V0, é"), (1, 8, String::from("expected a number or a symbol")));
+    }
+}
@@ -0,0 +1,65 @@
+// Big-endian integers, for the binary file formats (save states and movies), and the
+// little-endian ones of the WAV files.
+
+use std::io;
+
+pub fn write_u16<W>(out: &mut W, value: u16) -> io::Result<()>
+    where W: io::Write
+{
+    out.write_all(&[(value >> 8) as u8, value as u8])
+}
+
+pub fn write_u32<W>(out: &mut W, value: u32) -> io::Result<()>
+    where W: io::Write
+{
+    write_u16(out, (value >> 16) as u16)?;
+    write_u16(out, value as u16)
+}
+
+pub fn write_u64<W>(out: &mut W, value: u64) -> io::Result<()>
+    where W: io::Write
+{
+    write_u32(out, (value >> 32) as u32)?;
+    write_u32(out, value as u32)
+}
+
+pub fn write_u16_le<W>(out: &mut W, value: u16) -> io::Result<()>
+    where W: io::Write
+{
+    out.write_all(&[value as u8, (value >> 8) as u8])
+}
+
+pub fn write_u32_le<W>(out: &mut W, value: u32) -> io::Result<()>
+    where W: io::Write
+{
+    write_u16_le(out, value as u16)?;
+    write_u16_le(out, (value >> 16) as u16)
+}
+
+pub fn read_u8<R>(input: &mut R) -> io::Result<u8>
+    where R: io::Read
+{
+    let mut byte = [0u8; 1];
+    input.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+pub fn read_u16<R>(input: &mut R) -> io::Result<u16>
+    where R: io::Read
+{
+    let mut bytes = [0u8; 2];
+    input.read_exact(&mut bytes)?;
+    Ok((bytes[0] as u16) << 8 | bytes[1] as u16)
+}
+
+pub fn read_u32<R>(input: &mut R) -> io::Result<u32>
+    where R: io::Read
+{
+    Ok((read_u16(input)? as u32) << 16 | read_u16(input)? as u32)
+}
+
+pub fn read_u64<R>(input: &mut R) -> io::Result<u64>
+    where R: io::Read
+{
+    Ok((read_u32(input)? as u64) << 32 | read_u32(input)? as u64)
+}
@@ -0,0 +1,49 @@
+// Real-time pacing of the emulation: a 60 Hz frame takes 1/60 s on the host, whatever the
+// number of instructions in it. Only the window uses it, headless runs go as fast as possible
+// since their emulated time doesn't depend on the host.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cpu::TIMER_FREQUENCY;
+
+// Speed of the slow motion mode.
+pub const SLOW_MOTION_SPEED: f64 = 0.25;
+
+// How late the frames may get before giving up on catching up with them.
+const MAX_LAG: u64 = 100;
+
+#[derive(Default)]
+pub struct Scheduler {
+    // When the current frame ends
+    deadline: Option<Instant>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler { deadline: None }
+    }
+
+    // Sleep until the end of the current frame, which lasts 1/60 s divided by the speed. Late
+    // frames are caught up with by not sleeping, unless they are too late.
+    pub fn wait(&mut self, speed: f64) {
+        let nanos = (1_000_000_000f64 / TIMER_FREQUENCY as f64 / speed) as u64;
+        let frame = Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32);
+
+        let now = Instant::now();
+        let deadline = self.deadline.unwrap_or(now) + frame;
+        if deadline > now {
+            thread::sleep(deadline - now);
+            self.deadline = Some(deadline);
+        } else if now - deadline > Duration::from_millis(MAX_LAG) {
+            self.deadline = Some(now);
+        } else {
+            self.deadline = Some(deadline);
+        }
+    }
+
+    // Start over from the current time, after running without waiting.
+    pub fn reset(&mut self) {
+        self.deadline = None;
+    }
+}
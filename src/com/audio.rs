@@ -0,0 +1,25 @@
+pub const PATTERN_SIZE: usize = 16;
+pub const DEFAULT_PITCH: u8 = 64;
+
+pub struct AudioCommunicator {
+    pub beeping: bool,
+    // XO-CHIP audio pattern: 128 1-bit samples, played in a loop while beeping
+    pub pattern: [u8; PATTERN_SIZE],
+    pub pitch: u8,
+}
+
+impl AudioCommunicator {
+    pub fn new() -> AudioCommunicator {
+        AudioCommunicator {
+            beeping: false,
+            pattern: [0u8; PATTERN_SIZE],
+            pitch: DEFAULT_PITCH,
+        }
+    }
+}
+
+impl Default for AudioCommunicator {
+    fn default() -> AudioCommunicator {
+        AudioCommunicator::new()
+    }
+}
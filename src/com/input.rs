@@ -0,0 +1,81 @@
+pub const KEY_COUNT: usize = 16;
+
+pub struct InputCommunicator {
+    pub keys: [bool; KEY_COUNT],
+    released: u16,
+}
+
+impl InputCommunicator {
+    pub fn new() -> InputCommunicator {
+        InputCommunicator {
+            keys: [false; KEY_COUNT],
+            released: 0u16,
+        }
+    }
+
+    pub fn press(&mut self, key: u8) {
+        self.keys[key as usize & 0x0F] = true;
+    }
+
+    pub fn release(&mut self, key: u8) {
+        let key = key & 0x0F;
+        if self.keys[key as usize] {
+            self.released |= 1 << key;
+        }
+        self.keys[key as usize] = false;
+    }
+
+    pub fn is_pressed(&self, key: u8) -> bool {
+        self.keys[key as usize & 0x0F]
+    }
+
+    // Pressed keys as a bitmask, bit N being key N.
+    pub fn state(&self) -> u16 {
+        self.keys
+            .iter()
+            .enumerate()
+            .filter(|&(_, pressed)| *pressed)
+            .fold(0u16, |state, (key, _)| state | 1 << key)
+    }
+
+    // Press and release the keys to match the bitmask, releases being reported as usual.
+    pub fn set_state(&mut self, state: u16) {
+        for key in 0..KEY_COUNT as u8 {
+            if state & 1 << key != 0 {
+                self.press(key);
+            } else {
+                self.release(key);
+            }
+        }
+    }
+
+    // Keys released since the last call to take_released, as a bitmask.
+    pub fn released(&self) -> u16 {
+        self.released
+    }
+
+    pub fn set_released(&mut self, released: u16) {
+        self.released = released;
+    }
+
+    // Forget the keys released so far, so that only new releases are reported.
+    pub fn clear_released(&mut self) {
+        self.released = 0u16;
+    }
+
+    // Returns the lowest key released since the last call, if any.
+    pub fn take_released(&mut self) -> Option<u8> {
+        if self.released == 0 {
+            return None;
+        }
+        let key = self.released.trailing_zeros() as u8;
+        self.released = 0u16;
+        Some(key)
+    }
+}
+
+impl Default for InputCommunicator {
+    fn default() -> InputCommunicator {
+        InputCommunicator::new()
+    }
+}
@@ -1,13 +1,23 @@
+pub mod audio;
+pub mod input;
 pub mod video;
 
+use self::audio::AudioCommunicator;
+use self::input::InputCommunicator;
 use self::video::VideoCommunicator;
 
 pub struct Communicator {
-    pub video: VideoCommunicator
+    pub video: VideoCommunicator,
+    pub audio: AudioCommunicator,
+    pub input: InputCommunicator,
 }
 
 impl Communicator {
     pub fn new() -> Communicator {
-        Communicator { video: VideoCommunicator::new() }
+        Communicator {
+            video: VideoCommunicator::new(),
+            audio: AudioCommunicator::new(),
+            input: InputCommunicator::new(),
+        }
     }
-}
\ No newline at end of file
+}
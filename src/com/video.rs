@@ -1,5 +1,11 @@
-const DEFAULT_DISPLAY_WIDTH: usize = 64;
-const DEFAULT_DISPLAY_HEIGHT: usize = 32;
+pub const DEFAULT_DISPLAY_WIDTH: usize = 64;
+pub const DEFAULT_DISPLAY_HEIGHT: usize = 32;
+pub const HIRES_DISPLAY_WIDTH: usize = 128;
+pub const HIRES_DISPLAY_HEIGHT: usize = 64;
+
+// Number of bitplanes (XO-CHIP). Each pixel of the display is a bitmask of the planes it is lit
+// on, so its value is also its color index in a 4-color palette.
+pub const PLANE_COUNT: usize = 2;
 
 pub enum VideoSignal {
     None,
@@ -8,19 +14,82 @@ pub enum VideoSignal {
 }
 
 pub struct VideoCommunicator {
-    pub display: Vec<bool>,
+    pub display: Vec<u8>,
     pub width: usize,
     pub height: usize,
+    pub planes: u8,
     pub signal: VideoSignal,
 }
 
 impl VideoCommunicator {
     pub fn new() -> VideoCommunicator {
         VideoCommunicator {
-            display: vec![false; DEFAULT_DISPLAY_WIDTH * DEFAULT_DISPLAY_HEIGHT],
+            display: vec![0u8; DEFAULT_DISPLAY_WIDTH * DEFAULT_DISPLAY_HEIGHT],
             width: DEFAULT_DISPLAY_WIDTH,
             height: DEFAULT_DISPLAY_HEIGHT,
+            planes: 1u8,
             signal: VideoSignal::None,
         }
     }
-}
\ No newline at end of file
+
+    // Change the display resolution, clearing it in the process.
+    pub fn resize(&mut self, width: usize, height: usize) {
+        self.display = vec![0u8; width * height];
+        self.width = width;
+        self.height = height;
+    }
+
+    // Clear the selected planes.
+    pub fn clear(&mut self) {
+        let planes = self.planes;
+        for pixel in self.display.iter_mut() {
+            *pixel &= !planes;
+        }
+    }
+
+    // The scrolling functions only move the selected planes.
+    pub fn scroll_down(&mut self, lines: usize) {
+        let (width, height) = (self.width, self.height);
+        self.scroll(|x, y| if y >= lines { Some((x, y - lines)) } else { None }, width, height);
+    }
+
+    pub fn scroll_up(&mut self, lines: usize) {
+        let (width, height) = (self.width, self.height);
+        self.scroll(|x, y| if y + lines < height { Some((x, y + lines)) } else { None },
+                    width,
+                    height);
+    }
+
+    pub fn scroll_right(&mut self, columns: usize) {
+        let (width, height) = (self.width, self.height);
+        self.scroll(|x, y| if x >= columns { Some((x - columns, y)) } else { None },
+                    width,
+                    height);
+    }
+
+    pub fn scroll_left(&mut self, columns: usize) {
+        let (width, height) = (self.width, self.height);
+        self.scroll(|x, y| if x + columns < width { Some((x + columns, y)) } else { None },
+                    width,
+                    height);
+    }
+
+    // Move every pixel of the selected planes, `source` giving the coordinates each pixel comes
+    // from (or None if it should be cleared).
+    fn scroll<F>(&mut self, source: F, width: usize, height: usize)
+        where F: Fn(usize, usize) -> Option<(usize, usize)>
+    {
+        let planes = self.planes;
+        let old = self.display.clone();
+        for y in 0..height {
+            for x in 0..width {
+                let moved = match source(x, y) {
+                    Some((src_x, src_y)) => old[src_y * width + src_x] & planes,
+                    None => 0,
+                };
+                let idx = y * width + x;
+                self.display[idx] = (old[idx] & !planes) | moved;
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::io;
+use std::fmt;
+use std::error::Error;
+
+use super::{CPU, CPUError, Registers};
+use super::inst::Instruction;
+
+// Execution error along with the state of the CPU when it happened. The failing instructions
+// don't advance the PC, so it still points to the faulting one.
+#[derive(Debug)]
+pub struct Fault {
+    pub error: CPUError,
+    pub pc: u16,
+    // Raw instruction words, None if the PC is outside of the memory
+    pub opcode: Option<u16>,
+    pub operand: Option<u16>,
+    pub instruction: Option<Instruction>,
+    pub registers: Registers,
+    pub cycles: u64,
+    pub frames: u64,
+}
+
+impl Fault {
+    // Write the whole report: error, opcode, instruction, registers and call stack.
+    pub fn write_report<W>(&self, out: &mut W) -> io::Result<()>
+        where W: io::Write
+    {
+        writeln!(out, "Execution error: {}", self.error)?;
+        writeln!(out, "PC: {:#06X}", self.pc)?;
+        match (self.opcode, self.operand) {
+            (Some(opcode), Some(operand)) => writeln!(out, "Opcode: {:04X} {:04X}", opcode, operand)?,
+            (Some(opcode), None) => writeln!(out, "Opcode: {:04X}", opcode)?,
+            (None, _) => writeln!(out, "Opcode: unreadable")?,
+        }
+        match self.instruction {
+            Some(ref inst) => writeln!(out, "Instruction: {}", inst)?,
+            None => writeln!(out, "Instruction: unknown")?,
+        }
+        writeln!(out, "Cycles: {}, frames: {}", self.cycles, self.frames)?;
+        write!(out, "{}", self.registers)
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {:#06X}", self.error, self.pc)?;
+        match self.instruction {
+            Some(ref inst) => write!(f, " ({})", inst),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Error for Fault {
+    fn description(&self) -> &str {
+        "execution error"
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        Some(&self.error)
+    }
+}
+
+impl CPU {
+    // Wrap an error returned by step in a fault report of the current state.
+    pub fn fault(&self, error: CPUError) -> Fault {
+        let pc = self.pc as usize;
+        let opcode = self.memory.read_dword(pc).ok();
+        let operand = match opcode {
+            Some(opcode) if Instruction::is_long(opcode) => self.memory.read_dword(pc + 2).ok(),
+            _ => None,
+        };
+        let instruction = match (opcode, operand) {
+            (Some(opcode), Some(operand)) => Instruction::from_binary_long(opcode, operand).ok(),
+            (Some(opcode), None) if !Instruction::is_long(opcode) => {
+                Instruction::from_binary(opcode).ok()
+            }
+            _ => None,
+        };
+        Fault {
+            error,
+            pc: self.pc,
+            opcode,
+            operand,
+            instruction,
+            registers: self.registers(),
+            cycles: self.cycles,
+            frames: self.frames,
+        }
+    }
+}
@@ -4,7 +4,7 @@ pub type DWord = u16;
 pub type Word = u8;
 pub type Nibble = u8;
 
-#[derive(Clone,Copy)]
+#[derive(Clone,Copy,Debug,PartialEq)]
 pub enum Value {
     Register(Nibble),
     Byte(Word),
@@ -19,14 +19,24 @@ impl fmt::Display for Value {
     }
 }
 
+#[derive(Debug,PartialEq)]
 pub enum Instruction {
     SYS(DWord),
     CLS,
     RET,
+    SCD(Nibble),
+    SCU(Nibble),
+    SCR,
+    SCL,
+    EXIT,
+    LOW,
+    HIGH,
     JP(DWord),
     CALL(DWord),
     SE(Nibble, Value),
     SNE(Nibble, Value),
+    SAVE(Nibble, Nibble),
+    LOAD(Nibble, Nibble),
     LD(Nibble, Value),
     ADD(Nibble, Value),
     OR(Nibble, Nibble),
@@ -37,6 +47,7 @@ pub enum Instruction {
     SUBN(Nibble, Nibble),
     SHL(Nibble, Nibble),
     LDI(DWord),
+    LDIL(DWord),
     JPO(DWord),
     RND(Nibble, Word),
     DRW(Nibble, Nibble, Nibble),
@@ -48,9 +59,15 @@ pub enum Instruction {
     LDSST(Nibble),
     ADDI(Nibble),
     LDF(Nibble),
+    LDHF(Nibble),
     LDB(Nibble),
     LDSBLK(Nibble),
     LDBLK(Nibble),
+    LDSR(Nibble),
+    LDR(Nibble),
+    PLANE(Nibble),
+    AUDIO,
+    PITCH(Nibble),
 }
 
 fn get_nibble(i: u16, offset: u16) -> u8 {
@@ -61,12 +78,103 @@ fn get_word(i: u16, offset: u16) -> u8 {
     (i >> (16 - offset - 8) & 0xFF) as u8
 }
 
+// Opcode with the X and Y registers set, the values out of range being truncated.
+fn with_xy(op: u16, x: Nibble, y: Nibble) -> u16 {
+    op | (x as u16 & 0xF) << 8 | (y as u16 & 0xF) << 4
+}
+
 impl Instruction {
+    // Whether the instruction starting with this dword takes a dword operand (XO-CHIP).
+    pub fn is_long(i: u16) -> bool {
+        i == 0xF000
+    }
+
+    pub fn from_binary_long(i: u16, operand: u16) -> Result<Instruction, &'static str> {
+        match i {
+            0xF000 => Ok(Instruction::LDIL(operand)),
+            _ => Instruction::from_binary(i),
+        }
+    }
+
+    // Inverse of from_binary. Long instructions give their first dword here, and their operand
+    // with long_operand.
+    pub fn to_binary(&self) -> u16 {
+        use self::Instruction::*;
+        match *self {
+            SYS(addr) => addr & 0xFFF,
+            CLS => 0x00E0,
+            RET => 0x00EE,
+            SCD(n) => 0x00C0 | n as u16 & 0xF,
+            SCU(n) => 0x00D0 | n as u16 & 0xF,
+            SCR => 0x00FB,
+            SCL => 0x00FC,
+            EXIT => 0x00FD,
+            LOW => 0x00FE,
+            HIGH => 0x00FF,
+            JP(addr) => 0x1000 | addr & 0xFFF,
+            CALL(addr) => 0x2000 | addr & 0xFFF,
+            SE(x, Value::Byte(b)) => with_xy(0x3000, x, 0) | b as u16,
+            SNE(x, Value::Byte(b)) => with_xy(0x4000, x, 0) | b as u16,
+            SE(x, Value::Register(y)) => with_xy(0x5000, x, y),
+            SAVE(x, y) => with_xy(0x5002, x, y),
+            LOAD(x, y) => with_xy(0x5003, x, y),
+            LD(x, Value::Byte(b)) => with_xy(0x6000, x, 0) | b as u16,
+            ADD(x, Value::Byte(b)) => with_xy(0x7000, x, 0) | b as u16,
+            LD(x, Value::Register(y)) => with_xy(0x8000, x, y),
+            OR(x, y) => with_xy(0x8001, x, y),
+            AND(x, y) => with_xy(0x8002, x, y),
+            XOR(x, y) => with_xy(0x8003, x, y),
+            ADD(x, Value::Register(y)) => with_xy(0x8004, x, y),
+            SUB(x, y) => with_xy(0x8005, x, y),
+            SHR(x, y) => with_xy(0x8006, x, y),
+            SUBN(x, y) => with_xy(0x8007, x, y),
+            SHL(x, y) => with_xy(0x800E, x, y),
+            SNE(x, Value::Register(y)) => with_xy(0x9000, x, y),
+            LDI(addr) => 0xA000 | addr & 0xFFF,
+            LDIL(_) => 0xF000,
+            JPO(addr) => 0xB000 | addr & 0xFFF,
+            RND(x, b) => with_xy(0xC000, x, 0) | b as u16,
+            DRW(x, y, n) => with_xy(0xD000, x, y) | n as u16 & 0xF,
+            SKP(x) => with_xy(0xE09E, x, 0),
+            SKNP(x) => with_xy(0xE0A1, x, 0),
+            PLANE(n) => with_xy(0xF001, n, 0),
+            AUDIO => 0xF002,
+            LDDT(x) => with_xy(0xF007, x, 0),
+            LDK(x) => with_xy(0xF00A, x, 0),
+            LDSDT(x) => with_xy(0xF015, x, 0),
+            LDSST(x) => with_xy(0xF018, x, 0),
+            ADDI(x) => with_xy(0xF01E, x, 0),
+            LDF(x) => with_xy(0xF029, x, 0),
+            LDHF(x) => with_xy(0xF030, x, 0),
+            LDB(x) => with_xy(0xF033, x, 0),
+            PITCH(x) => with_xy(0xF03A, x, 0),
+            LDSBLK(x) => with_xy(0xF055, x, 0),
+            LDBLK(x) => with_xy(0xF065, x, 0),
+            LDSR(x) => with_xy(0xF075, x, 0),
+            LDR(x) => with_xy(0xF085, x, 0),
+        }
+    }
+
+    // The dword following a long instruction (XO-CHIP), None for the other ones.
+    pub fn long_operand(&self) -> Option<u16> {
+        match *self {
+            Instruction::LDIL(addr) => Some(addr),
+            _ => None,
+        }
+    }
+
     pub fn from_binary(i: u16) -> Result<Instruction, &'static str> {
         use self::Instruction::*;
         match i {
             0x00EE => Ok(RET),
             0x00E0 => Ok(CLS),
+            0x00FB => Ok(SCR),
+            0x00FC => Ok(SCL),
+            0x00FD => Ok(EXIT),
+            0x00FE => Ok(LOW),
+            0x00FF => Ok(HIGH),
+            _ if i & 0xFFF0 == 0x00C0 => Ok(SCD(get_nibble(i, 12))),
+            _ if i & 0xFFF0 == 0x00D0 => Ok(SCU(get_nibble(i, 12))),
             _ if i & 0xF000 == 0x0000 => Ok(SYS(i & 0xFFF)),
             _ if i & 0xF000 == 0x1000 => Ok(JP(i & 0xFFF)),
             _ if i & 0xF000 == 0x2000 => Ok(CALL(i & 0xFFF)),
@@ -75,6 +183,8 @@ impl Instruction {
             _ if i & 0xF00F == 0x5000 => {
                 Ok(SE(get_nibble(i, 4), Value::Register(get_nibble(i, 8))))
             }
+            _ if i & 0xF00F == 0x5002 => Ok(SAVE(get_nibble(i, 4), get_nibble(i, 8))),
+            _ if i & 0xF00F == 0x5003 => Ok(LOAD(get_nibble(i, 4), get_nibble(i, 8))),
             _ if i & 0xF000 == 0x6000 => Ok(LD(get_nibble(i, 4), Value::Byte(get_word(i, 8)))),
             _ if i & 0xF000 == 0x7000 => Ok(ADD(get_nibble(i, 4), Value::Byte(get_word(i, 8)))),
             _ if i & 0xF00F == 0x8000 => {
@@ -101,15 +211,22 @@ impl Instruction {
             }
             _ if i & 0xF0FF == 0xE09E => Ok(SKP(get_nibble(i, 4))),
             _ if i & 0xF0FF == 0xE0A1 => Ok(SKNP(get_nibble(i, 4))),
+            0xF000 => Err("instruction needs an operand"),
+            0xF002 => Ok(AUDIO),
+            _ if i & 0xF0FF == 0xF001 => Ok(PLANE(get_nibble(i, 4))),
             _ if i & 0xF0FF == 0xF007 => Ok(LDDT(get_nibble(i, 4))),
             _ if i & 0xF0FF == 0xF00A => Ok(LDK(get_nibble(i, 4))),
             _ if i & 0xF0FF == 0xF015 => Ok(LDSDT(get_nibble(i, 4))),
             _ if i & 0xF0FF == 0xF018 => Ok(LDSST(get_nibble(i, 4))),
             _ if i & 0xF0FF == 0xF01E => Ok(ADDI(get_nibble(i, 4))),
             _ if i & 0xF0FF == 0xF029 => Ok(LDF(get_nibble(i, 4))),
+            _ if i & 0xF0FF == 0xF030 => Ok(LDHF(get_nibble(i, 4))),
+            _ if i & 0xF0FF == 0xF03A => Ok(PITCH(get_nibble(i, 4))),
             _ if i & 0xF0FF == 0xF033 => Ok(LDB(get_nibble(i, 4))),
             _ if i & 0xF0FF == 0xF055 => Ok(LDSBLK(get_nibble(i, 4))),
             _ if i & 0xF0FF == 0xF065 => Ok(LDBLK(get_nibble(i, 4))),
+            _ if i & 0xF0FF == 0xF075 => Ok(LDSR(get_nibble(i, 4))),
+            _ if i & 0xF0FF == 0xF085 => Ok(LDR(get_nibble(i, 4))),
             _ => Err("instruction does not exist"),
         }
     }
@@ -122,20 +239,30 @@ impl fmt::Display for Instruction {
             SYS(ref addr) => write!(f, "SYS {:#X}", addr),
             CLS => write!(f, "CLS"),
             RET => write!(f, "RET"),
+            SCD(ref n) => write!(f, "SCD {}", n),
+            SCU(ref n) => write!(f, "SCU {}", n),
+            SCR => write!(f, "SCR"),
+            SCL => write!(f, "SCL"),
+            EXIT => write!(f, "EXIT"),
+            LOW => write!(f, "LOW"),
+            HIGH => write!(f, "HIGH"),
             JP(ref addr) => write!(f, "JP {:#X}", addr),
             CALL(ref addr) => write!(f, "CALL {:#X}", addr),
             SE(ref reg, ref v) => write!(f, "SE V{}, {}", reg, v),
             SNE(ref reg, ref v) => write!(f, "SNE V{}, {}", reg, v),
+            SAVE(ref reg1, ref reg2) => write!(f, "SAVE V{}, V{}", reg1, reg2),
+            LOAD(ref reg1, ref reg2) => write!(f, "LOAD V{}, V{}", reg1, reg2),
             LD(ref reg, ref v) => write!(f, "LD V{}, {}", reg, v),
             ADD(ref reg, ref v) => write!(f, "ADD V{}, {}", reg, v),
             OR(ref reg1, ref reg2) => write!(f, "OR V{}, V{}", reg1, reg2),
             AND(ref reg1, ref reg2) => write!(f, "AND V{}, V{}", reg1, reg2),
             XOR(ref reg1, ref reg2) => write!(f, "XOR V{}, V{}", reg1, reg2),
             SUB(ref reg1, ref reg2) => write!(f, "SUB V{}, V{}", reg1, reg2),
-            SHR(ref reg, _) => write!(f, "SHR V{}", reg),
+            SHR(ref reg1, ref reg2) => write!(f, "SHR V{}, V{}", reg1, reg2),
             SUBN(ref reg1, ref reg2) => write!(f, "SUBN V{}, V{}", reg1, reg2),
-            SHL(ref reg, _) => write!(f, "SHL V{}", reg),
+            SHL(ref reg1, ref reg2) => write!(f, "SHL V{}, V{}", reg1, reg2),
             LDI(ref addr) => write!(f, "LD I, {:#X}", addr),
+            LDIL(ref addr) => write!(f, "LD I, LONG {:#X}", addr),
             JPO(ref addr) => write!(f, "JP V0, {:#X}", addr),
             RND(ref reg, ref b) => write!(f, "RND V{}, {}", reg, b),
             DRW(ref reg1, ref reg2, ref n) => write!(f, "DRW V{}, V{}, {}", reg1, reg2, n),
@@ -147,9 +274,45 @@ impl fmt::Display for Instruction {
             LDSST(ref reg) => write!(f, "LD ST, V{}", reg),
             ADDI(ref reg) => write!(f, "ADD I, V{}", reg),
             LDF(ref reg) => write!(f, "LD F, V{}", reg),
+            LDHF(ref reg) => write!(f, "LD HF, V{}", reg),
             LDB(ref reg) => write!(f, "LD B, V{}", reg),
             LDSBLK(ref reg) => write!(f, "LD [I], V{}", reg),
             LDBLK(ref reg) => write!(f, "LD V{}, [I]", reg),
+            LDSR(ref reg) => write!(f, "LD R, V{}", reg),
+            LDR(ref reg) => write!(f, "LD V{}, R", reg),
+            PLANE(ref n) => write!(f, "PLANE {}", n),
+            AUDIO => write!(f, "AUDIO"),
+            PITCH(ref reg) => write!(f, "PITCH V{}", reg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Instruction;
+
+    #[test]
+    fn binary_round_trip() {
+        for op in 0..=0xFFFFu16 {
+            if Instruction::is_long(op) {
+                continue;
+            }
+            if let Ok(inst) = Instruction::from_binary(op) {
+                assert_eq!(inst.to_binary(), op, "{:04X} is {}", op, inst);
+                assert_eq!(inst.long_operand(), None);
+            }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn long_binary_round_trip() {
+        for operand in [0x0000, 0x0200, 0x1234, 0xFFFF].iter() {
+            let inst = Instruction::from_binary_long(0xF000, *operand).unwrap();
+            assert_eq!(inst, Instruction::LDIL(*operand));
+            assert!(Instruction::is_long(inst.to_binary()));
+            assert_eq!(inst.long_operand(), Some(*operand));
+        }
+        // The next dword is only an operand after a long instruction
+        assert_eq!(Instruction::from_binary_long(0x00E0, 0x1234), Ok(Instruction::CLS));
+    }
+}
@@ -1,17 +1,75 @@
-extern crate rand;
-
 pub mod inst;
 mod error;
+mod fault;
+mod quirks;
+mod rng;
+mod snapshot;
+mod timer;
+mod timing;
+mod undo;
 
 use std::io;
+use std::fmt;
+use std::cmp;
 
+use rand;
 use memory;
 use com::Communicator;
-use com::video::{VideoCommunicator, VideoSignal};
+use com::video::{self, VideoCommunicator, VideoSignal};
 pub use self::error::CPUError;
+pub use self::fault::Fault;
+pub use self::quirks::Quirks;
+pub use self::rng::RndMode;
+pub use self::snapshot::SnapshotError;
+pub use self::timer::{DEFAULT_CLOCK_RATE, TIMER_FREQUENCY};
+pub use self::timing::Timing;
+pub use self::undo::Undo;
+use self::rng::Rng;
+use self::timer::Timers;
+use self::undo::Journal;
 
 const V_REGISTER_COUNT: usize = 16;
 const STACK_SIZE: usize = 16;
+const RPL_FLAG_COUNT: usize = 16;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct Registers {
+    pub v: [u8; V_REGISTER_COUNT],
+    pub i: u16,
+    pub pc: u16,
+    pub sp: u8,
+    pub stack: [u16; STACK_SIZE],
+    pub dt: u8,
+    pub st: u8,
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (idx, v) in self.v.iter().enumerate() {
+            write!(f, "V{:X}={:02X}{}", idx, v, if idx % 8 == 7 { "\n" } else { " " })?;
+        }
+        writeln!(f,
+                 "I={:04X} PC={:04X} SP={:02X} DT={:02X} ST={:02X}",
+                 self.i,
+                 self.pc,
+                 self.sp,
+                 self.dt,
+                 self.st)?;
+        write!(f, "Stack:")?;
+        for addr in self.stack.iter().take(self.sp as usize) {
+            write!(f, " {:04X}", addr)?;
+        }
+        writeln!(f)
+    }
+}
+
+// What happens to the parts of a sprite drawn past the edge of the screen.
+// In both cases, the sprite origin itself is wrapped around the screen.
+#[derive(Clone,Copy,PartialEq)]
+pub enum SpriteMode {
+    Wrap,
+    Clip,
+}
 
 pub struct CPU {
     v_registers: [u8; V_REGISTER_COUNT],
@@ -19,20 +77,54 @@ pub struct CPU {
     pc: u16,
     sp: u8,
     stack: [u16; STACK_SIZE],
+    rpl_flags: [u8; RPL_FLAG_COUNT],
     memory: memory::Memory,
+    timers: Timers,
+    timing: Timing,
+    // Instructions per second, with the flat timing
+    clock_rate: u32,
+    rng: Rng,
+    waiting_key: bool,
+    waiting_vblank: bool,
+    quirks: Quirks,
+    cycles: u64,
+    frames: u64,
     running: bool,
+    // Changes of the current step, when it may be undone
+    journal: Option<Journal>,
+}
+
+impl Default for CPU {
+    fn default() -> CPU {
+        CPU::new()
+    }
 }
 
 impl CPU {
     pub fn new() -> CPU {
+        CPU::with_ram_size(memory::RAM_SIZE)
+    }
+
+    pub fn with_ram_size(ram_size: usize) -> CPU {
         CPU {
             v_registers: [0u8; V_REGISTER_COUNT],
             i_register: 0u16,
             pc: 0x200u16,
             sp: 0u8,
             stack: [0u16; STACK_SIZE],
-            memory: memory::Memory::new(),
+            rpl_flags: [0u8; RPL_FLAG_COUNT],
+            memory: memory::Memory::with_size(ram_size),
+            timers: Timers::new(timer::DEFAULT_CLOCK_RATE),
+            timing: Timing::Flat,
+            clock_rate: timer::DEFAULT_CLOCK_RATE,
+            rng: Rng::new(rand::random()),
+            waiting_key: false,
+            waiting_vblank: false,
+            quirks: Quirks::default(),
+            cycles: 0u64,
+            frames: 0u64,
             running: true,
+            journal: None,
         }
     }
 
@@ -42,52 +134,190 @@ impl CPU {
         self.memory.dump(out)
     }
 
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    // Number of instructions executed per second of emulated time, at least one per frame.
+    pub fn set_clock_rate(&mut self, clock_rate: u32) {
+        self.clock_rate = cmp::max(clock_rate, timer::TIMER_FREQUENCY);
+        self.update_clock_rate();
+    }
+
+    pub fn set_timing(&mut self, timing: Timing) {
+        self.timing = timing;
+        self.update_clock_rate();
+    }
+
+    fn update_clock_rate(&mut self) {
+        let clock_rate = match self.timing {
+            Timing::Flat => self.clock_rate,
+            Timing::CosmacVip => timing::VIP_CYCLES_PER_FRAME * timer::TIMER_FREQUENCY,
+        };
+        self.timers.set_clock_rate(clock_rate);
+    }
+
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng.seed(seed);
+    }
+
+    pub fn set_rnd_mode(&mut self, mode: RndMode) {
+        self.rng.set_mode(mode);
+    }
+
     pub fn load_rom(&mut self, rom: Vec<u8>) {
         self.memory.load_rom(rom);
     }
 
     pub fn step(&mut self, com: &mut Communicator) -> Result<(), CPUError> {
+        if self.waiting_vblank {
+            if self.advance_time(com, 1) {
+                self.waiting_vblank = false;
+            }
+            return Ok(());
+        }
+
         let addr = self.pc as usize;
         let inst_dword = self.read_dword(addr)?;
-        let inst = inst::Instruction::from_binary(inst_dword)
-            .map_err(|reason| CPUError::ParsingError(reason))?;
+        let inst = if inst::Instruction::is_long(inst_dword) {
+            let operand = self.read_dword(addr + 2)?;
+            inst::Instruction::from_binary_long(inst_dword, operand)
+        } else {
+            inst::Instruction::from_binary(inst_dword)
+        };
+        let inst = inst.map_err(CPUError::ParsingError)?;
+
+        let cost = match self.timing {
+            Timing::Flat => 1,
+            Timing::CosmacVip => self.vip_cycles(&inst),
+        };
+        let skip = timing::is_skip(&inst);
 
         //println!("{:#X}\t| {}", self.pc, inst);
-        self.execute(inst, com)
+        let pc = self.pc;
+        self.execute(inst, com)?;
+
+        let skipped = skip && self.pc.wrapping_sub(pc) > 2;
+        let cost = if self.timing == Timing::CosmacVip && skipped {
+            cost + timing::VIP_SKIP_CYCLES
+        } else {
+            cost
+        };
+        self.advance_time(com, cost);
+        Ok(())
     }
 
     pub fn is_running(&self) -> bool {
         self.running
     }
 
+    // Number of steps executed so far.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Number of 60 Hz frames (timer ticks) elapsed so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    // Time elapsed in the current frame, out of the given number of units per frame.
+    pub fn frame_position(&self, units: u64) -> u64 {
+        self.timers.elapsed as u64 * units / self.timers.clock_rate() as u64
+    }
+
+    pub fn memory(&self) -> &memory::Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut memory::Memory {
+        &mut self.memory
+    }
+
+    pub fn set_registers(&mut self, registers: &Registers) {
+        self.v_registers = registers.v;
+        self.i_register = registers.i;
+        self.pc = registers.pc;
+        self.sp = cmp::min(registers.sp, STACK_SIZE as u8);
+        self.stack = registers.stack;
+        self.timers.delay = registers.dt;
+        self.timers.sound = registers.st;
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            v: self.v_registers,
+            i: self.i_register,
+            pc: self.pc,
+            sp: self.sp,
+            stack: self.stack,
+            dt: self.timers.delay,
+            st: self.timers.sound,
+        }
+    }
+
+    // Advance emulated time by the cost of a step, returns true if a new frame started.
+    fn advance_time(&mut self, com: &mut Communicator, cost: u32) -> bool {
+        self.cycles += 1;
+        let ticks = self.timers.step(cost);
+        self.frames += ticks as u64;
+        com.audio.beeping = self.timers.sound > 0;
+        ticks > 0
+    }
+
     fn execute(&mut self, inst: inst::Instruction, com: &mut Communicator) -> Result<(), CPUError> {
         use self::inst::Instruction::*;
+        if let Some(ref mut journal) = self.journal {
+            journal.before(&inst, com);
+        }
         match inst {
             SYS(addr) => Ok(self.op_sys(addr)),
             CLS => Ok(self.op_cls(com)),
             RET => self.op_ret(),
+            SCD(lines) => self.op_scd(lines, com),
+            SCU(lines) => self.op_scu(lines, com),
+            SCR => self.op_scr(com),
+            SCL => self.op_scl(com),
+            EXIT => self.op_exit(),
+            LOW => self.op_low(com),
+            HIGH => self.op_high(com),
             JP(addr) => Ok(self.op_jp(addr)),
             CALL(addr) => self.op_call(addr),
             SE(reg, val) => self.op_se(reg, val),
             SNE(reg, val) => self.op_sne(reg, val),
+            SAVE(first, last) => self.op_save(first, last),
+            LOAD(first, last) => self.op_load(first, last),
             LD(reg, val) => self.op_ld(reg, val),
             ADD(reg, val) => self.op_add(reg, val),
             OR(l_reg, r_reg) => self.op_or(l_reg, r_reg),
             AND(l_reg, r_reg) => self.op_and(l_reg, r_reg),
             XOR(l_reg, r_reg) => self.op_xor(l_reg, r_reg),
             SUB(l_reg, r_reg) => self.op_sub(l_reg, r_reg),
-            SHR(reg, _) => self.op_shr(reg),
+            SHR(l_reg, r_reg) => self.op_shr(l_reg, r_reg),
             SUBN(l_reg, r_reg) => self.op_subn(l_reg, r_reg),
-            SHL(reg, _) => self.op_shl(reg),
+            SHL(l_reg, r_reg) => self.op_shl(l_reg, r_reg),
             LDI(addr) => Ok(self.op_ldi(addr)),
+            LDIL(addr) => self.op_ldil(addr),
             JPO(addr) => Ok(self.op_jpo(addr)),
             RND(reg, mask) => self.op_rnd(reg, mask),
             DRW(x_reg, y_reg, size) => self.op_drw(x_reg, y_reg, size, com),
+            SKP(reg) => self.op_skp(reg, com),
+            SKNP(reg) => self.op_sknp(reg, com),
+            LDDT(reg) => self.op_lddt(reg),
+            LDK(reg) => self.op_ldk(reg, com),
+            LDSDT(reg) => self.op_ldsdt(reg),
+            LDSST(reg) => self.op_ldsst(reg),
             ADDI(reg) => self.op_addi(reg),
+            LDF(reg) => self.op_ldf(reg),
+            LDHF(reg) => self.op_ldhf(reg),
             LDB(reg) => self.op_ldb(reg),
             LDSBLK(reg) => self.op_ldsblk(reg),
             LDBLK(reg) => self.op_ldblk(reg),
-            _ => unimplemented!(),
+            LDSR(reg) => self.op_ldsr(reg),
+            LDR(reg) => self.op_ldr(reg),
+            PLANE(planes) => self.op_plane(planes, com),
+            AUDIO => self.op_audio(com),
+            PITCH(reg) => self.op_pitch(reg, com),
         }
     }
 
@@ -95,16 +325,65 @@ impl CPU {
         if addr == 0x100 {
             self.running = false;
         } else {
-            self.pc += 2;
+            self.pc = self.pc.wrapping_add(2);
         }
     }
 
     fn op_cls(&mut self, com: &mut Communicator) {
-        for pixel in com.video.display.iter_mut() {
-            *pixel = false;
-        }
-        com.video.signal = VideoSignal::Clear;
-        self.pc += 2;
+        com.video.clear();
+        com.video.signal = if com.video.display.iter().all(|pixel| *pixel == 0) {
+            VideoSignal::Clear
+        } else {
+            VideoSignal::Refresh
+        };
+        self.pc = self.pc.wrapping_add(2);
+    }
+
+    fn op_scd(&mut self, lines: inst::Nibble, com: &mut Communicator) -> Result<(), CPUError> {
+        com.video.scroll_down(lines as usize);
+        com.video.signal = VideoSignal::Refresh;
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    fn op_scu(&mut self, lines: inst::Nibble, com: &mut Communicator) -> Result<(), CPUError> {
+        com.video.scroll_up(lines as usize);
+        com.video.signal = VideoSignal::Refresh;
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    fn op_scr(&mut self, com: &mut Communicator) -> Result<(), CPUError> {
+        com.video.scroll_right(4);
+        com.video.signal = VideoSignal::Refresh;
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    fn op_scl(&mut self, com: &mut Communicator) -> Result<(), CPUError> {
+        com.video.scroll_left(4);
+        com.video.signal = VideoSignal::Refresh;
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    fn op_exit(&mut self) -> Result<(), CPUError> {
+        self.running = false;
+        Ok(())
+    }
+
+    fn op_low(&mut self, com: &mut Communicator) -> Result<(), CPUError> {
+        com.video.resize(video::DEFAULT_DISPLAY_WIDTH, video::DEFAULT_DISPLAY_HEIGHT);
+        com.video.signal = VideoSignal::Refresh;
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    fn op_high(&mut self, com: &mut Communicator) -> Result<(), CPUError> {
+        com.video.resize(video::HIRES_DISPLAY_WIDTH, video::HIRES_DISPLAY_HEIGHT);
+        com.video.signal = VideoSignal::Refresh;
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
     }
 
     fn op_ret(&mut self) -> Result<(), CPUError> {
@@ -124,7 +403,7 @@ impl CPU {
     fn op_call(&mut self, addr: inst::DWord) -> Result<(), CPUError> {
         let sp_usize = self.sp as usize;
         if sp_usize < STACK_SIZE {
-            self.stack[sp_usize] = self.pc + 2;
+            self.stack[sp_usize] = self.pc.wrapping_add(2);
             self.sp += 1;
             self.pc = addr;
             Ok(())
@@ -137,7 +416,8 @@ impl CPU {
         let first = self.get_register(reg)?;
         let second = self.unwrap_value(val)?;
 
-        self.pc += if first == second { 4 } else { 2 };
+        let size = if first == second { self.skip_size() } else { 2 };
+        self.pc = self.pc.wrapping_add(size);
         Ok(())
     }
 
@@ -145,7 +425,30 @@ impl CPU {
         let first = self.get_register(reg)?;
         let second = self.unwrap_value(val)?;
 
-        self.pc += if first == second { 2 } else { 4 };
+        let size = if first == second { 2 } else { self.skip_size() };
+        self.pc = self.pc.wrapping_add(size);
+        Ok(())
+    }
+
+    fn op_save(&mut self, first: inst::Nibble, last: inst::Nibble) -> Result<(), CPUError> {
+        let addr = self.i_register as usize;
+        for (offset, reg) in register_range(first, last).enumerate() {
+            let reg_val = self.get_register(reg)?;
+            self.write_word(addr + offset, reg_val)?;
+        }
+
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    fn op_load(&mut self, first: inst::Nibble, last: inst::Nibble) -> Result<(), CPUError> {
+        let addr = self.i_register as usize;
+        for (offset, reg) in register_range(first, last).enumerate() {
+            let mem_val = self.read_word(addr + offset)?;
+            self.set_register(reg, mem_val)?;
+        }
+
+        self.pc = self.pc.wrapping_add(2);
         Ok(())
     }
 
@@ -153,7 +456,7 @@ impl CPU {
         let val = self.unwrap_value(val)?;
         self.set_register(reg, val)?;
 
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         Ok(())
     }
 
@@ -167,7 +470,7 @@ impl CPU {
         }
         self.set_register(reg, (dst & 0xFF) as u8)?;
 
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         Ok(())
     }
 
@@ -175,8 +478,11 @@ impl CPU {
         let left = self.get_register(l_reg)?;
         let right = self.get_register(r_reg)?;
         self.set_register(l_reg, left | right)?;
+        if self.quirks.logic_reset_vf {
+            self.set_register(15, 0)?;
+        }
 
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         Ok(())
     }
 
@@ -184,8 +490,11 @@ impl CPU {
         let left = self.get_register(l_reg)?;
         let right = self.get_register(r_reg)?;
         self.set_register(l_reg, left & right)?;
+        if self.quirks.logic_reset_vf {
+            self.set_register(15, 0)?;
+        }
 
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         Ok(())
     }
 
@@ -193,8 +502,11 @@ impl CPU {
         let left = self.get_register(l_reg)?;
         let right = self.get_register(r_reg)?;
         self.set_register(l_reg, left ^ right)?;
+        if self.quirks.logic_reset_vf {
+            self.set_register(15, 0)?;
+        }
 
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         Ok(())
     }
 
@@ -204,16 +516,16 @@ impl CPU {
         self.set_register(15, if left > right { 1 } else { 0 })?;
         self.set_register(l_reg, left.wrapping_sub(right))?;
 
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         Ok(())
     }
 
-    fn op_shr(&mut self, reg: inst::Nibble) -> Result<(), CPUError> {
-        let reg_val = self.get_register(reg)?;
+    fn op_shr(&mut self, l_reg: inst::Nibble, r_reg: inst::Nibble) -> Result<(), CPUError> {
+        let reg_val = self.get_register(if self.quirks.shift_vy { r_reg } else { l_reg })?;
+        self.set_register(l_reg, reg_val >> 1)?;
         self.set_register(15, if reg_val & 1 == 1 { 1 } else { 0 })?;
-        self.set_register(reg, reg_val >> 1)?;
 
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         Ok(())
     }
 
@@ -223,32 +535,40 @@ impl CPU {
         self.set_register(15, if right > left { 1 } else { 0 })?;
         self.set_register(l_reg, right.wrapping_sub(left))?;
 
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         Ok(())
     }
 
-    fn op_shl(&mut self, reg: inst::Nibble) -> Result<(), CPUError> {
-        let reg_val = self.get_register(reg)?;
+    fn op_shl(&mut self, l_reg: inst::Nibble, r_reg: inst::Nibble) -> Result<(), CPUError> {
+        let reg_val = self.get_register(if self.quirks.shift_vy { r_reg } else { l_reg })?;
+        self.set_register(l_reg, reg_val << 1)?;
         self.set_register(15, if reg_val & 0x80 == 0x80 { 1 } else { 0 })?;
-        self.set_register(reg, reg_val << 1)?;
 
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         Ok(())
     }
 
     fn op_ldi(&mut self, addr: inst::DWord) {
         self.i_register = addr;
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
+    }
+
+    fn op_ldil(&mut self, addr: inst::DWord) -> Result<(), CPUError> {
+        self.i_register = addr;
+        self.pc = self.pc.wrapping_add(4);
+        Ok(())
     }
 
     fn op_jpo(&mut self, addr: inst::DWord) {
-        self.pc = self.v_registers[0] as u16 + addr;
+        let reg = if self.quirks.jump_vx { (addr >> 8) as usize } else { 0 };
+        self.pc = self.v_registers[reg] as u16 + addr;
     }
 
     fn op_rnd(&mut self, reg: inst::Nibble, mask: inst::Word) -> Result<(), CPUError> {
-        self.set_register(reg, rand::random::<u8>() & mask)?;
+        let value = self.rng.next_byte(&self.memory);
+        self.set_register(reg, value & mask)?;
 
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         Ok(())
     }
 
@@ -258,21 +578,97 @@ impl CPU {
               size: inst::Nibble,
               com: &mut Communicator)
               -> Result<(), CPUError> {
-        let x = self.get_register(x_reg)? as usize;
-        let y = self.get_register(y_reg)? as usize;
-        let size = size as usize;
+        let x = self.get_register(x_reg)? as usize % com.video.width;
+        let y = self.get_register(y_reg)? as usize % com.video.height;
+        // A size of 0 draws a 16x16 sprite (SUPER-CHIP), stored as two bytes per line
+        let (width, height) = if size == 0 { (16, 16) } else { (8, size as usize) };
+        let row_size = width / 8;
         let mut collision = false;
 
-        for line in 0..size {
-            let i_register = self.i_register as usize;
-            let pixels = self.read_word(i_register + line)?;
-            for column in 0..8 {
-                let pixel = (pixels & (0x80 >> column)) != 0;
-                collision = self.set_pixel(x + column, y + line, pixel, &mut com.video) | collision;
+        // With several planes selected (XO-CHIP), the sprite data of each plane follows the
+        // previous one
+        let mut addr = self.i_register as usize;
+        for plane in 0..video::PLANE_COUNT {
+            let plane = 1 << plane;
+            if com.video.planes & plane == 0 {
+                continue;
             }
+
+            for line in 0..height {
+                for byte in 0..row_size {
+                    let pixels = self.read_word(addr + line * row_size + byte)?;
+                    for column in 0..8 {
+                        let pixel = (pixels & (0x80 >> column)) != 0;
+                        collision |= self.set_pixel(x + byte * 8 + column,
+                                                    y + line,
+                                                    pixel,
+                                                    plane,
+                                                    &mut com.video);
+                    }
+                }
+            }
+            addr += height * row_size;
         }
+        self.set_register(15, if collision { 1 } else { 0 })?;
 
         com.video.signal = VideoSignal::Refresh;
+        // The VIP timing already charges the wait for the next frame
+        self.waiting_vblank = self.quirks.display_wait && self.timing == Timing::Flat;
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    fn op_skp(&mut self, reg: inst::Nibble, com: &mut Communicator) -> Result<(), CPUError> {
+        let key = self.get_register(reg)?;
+
+        let size = if com.input.is_pressed(key) { self.skip_size() } else { 2 };
+        self.pc = self.pc.wrapping_add(size);
+        Ok(())
+    }
+
+    fn op_sknp(&mut self, reg: inst::Nibble, com: &mut Communicator) -> Result<(), CPUError> {
+        let key = self.get_register(reg)?;
+
+        let size = if com.input.is_pressed(key) { 2 } else { self.skip_size() };
+        self.pc = self.pc.wrapping_add(size);
+        Ok(())
+    }
+
+    fn op_lddt(&mut self, reg: inst::Nibble) -> Result<(), CPUError> {
+        let delay = self.timers.delay;
+        self.set_register(reg, delay)?;
+
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    fn op_ldk(&mut self, reg: inst::Nibble, com: &mut Communicator) -> Result<(), CPUError> {
+        // Wait for a key to be pressed then released. The PC isn't advanced until then, so
+        // this instruction is executed again on each step (and the timers keep running).
+        if !self.waiting_key {
+            com.input.clear_released();
+            self.waiting_key = true;
+        }
+
+        if let Some(key) = com.input.take_released() {
+            self.waiting_key = false;
+            self.set_register(reg, key)?;
+            self.pc = self.pc.wrapping_add(2);
+        }
+        Ok(())
+    }
+
+    fn op_ldsdt(&mut self, reg: inst::Nibble) -> Result<(), CPUError> {
+        self.timers.delay = self.get_register(reg)?;
+
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    fn op_ldsst(&mut self, reg: inst::Nibble) -> Result<(), CPUError> {
+        self.timers.sound = self.get_register(reg)?;
+
+        self.pc = self.pc.wrapping_add(2);
         Ok(())
     }
 
@@ -280,7 +676,23 @@ impl CPU {
         let reg_val = self.get_register(reg)?;
         self.i_register = self.i_register.wrapping_add(reg_val as u16);
 
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    fn op_ldf(&mut self, reg: inst::Nibble) -> Result<(), CPUError> {
+        let digit = self.get_register(reg)? & 0x0F;
+        self.i_register = (memory::FONT_ADDRESS + digit as usize * 5) as u16;
+
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    fn op_ldhf(&mut self, reg: inst::Nibble) -> Result<(), CPUError> {
+        let digit = self.get_register(reg)? & 0x0F;
+        self.i_register = (memory::BIG_FONT_ADDRESS + digit as usize * 10) as u16;
+
+        self.pc = self.pc.wrapping_add(2);
         Ok(())
     }
 
@@ -291,32 +703,95 @@ impl CPU {
         self.write_word(addr + 1, reg_val % 100 / 10)?;
         self.write_word(addr + 2, reg_val % 10)?;
 
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         Ok(())
     }
 
     fn op_ldsblk(&mut self, reg: inst::Nibble) -> Result<(), CPUError> {
         let addr = self.i_register as usize;
-        for i in 0..reg {
+        for i in 0..reg + 1 {
             let reg_val = self.get_register(i)?;
             self.write_word(addr + i as usize, reg_val)?;
         }
+        self.increment_i_after_load_store(reg);
 
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         Ok(())
     }
 
+    fn increment_i_after_load_store(&mut self, reg: inst::Nibble) {
+        if self.quirks.load_store_increment_i {
+            let increment = if self.quirks.load_store_increment_i_by_x {
+                reg as u16
+            } else {
+                reg as u16 + 1
+            };
+            self.i_register = self.i_register.wrapping_add(increment);
+        }
+    }
+
     fn op_ldblk(&mut self, reg: inst::Nibble) -> Result<(), CPUError> {
         let addr = self.i_register as usize;
-        for i in 0..reg {
+        for i in 0..reg + 1 {
             let mem_val = self.read_word(addr + i as usize)?;
             self.set_register(i, mem_val)?;
         }
+        self.increment_i_after_load_store(reg);
+
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    fn op_ldsr(&mut self, reg: inst::Nibble) -> Result<(), CPUError> {
+        for i in 0..reg + 1 {
+            self.rpl_flags[i as usize] = self.get_register(i)?;
+        }
+
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    fn op_ldr(&mut self, reg: inst::Nibble) -> Result<(), CPUError> {
+        for i in 0..reg + 1 {
+            let flag = self.rpl_flags[i as usize];
+            self.set_register(i, flag)?;
+        }
+
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    fn op_plane(&mut self, planes: inst::Nibble, com: &mut Communicator) -> Result<(), CPUError> {
+        com.video.planes = planes & 0x03;
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    fn op_audio(&mut self, com: &mut Communicator) -> Result<(), CPUError> {
+        let addr = self.i_register as usize;
+        for i in 0..com.audio.pattern.len() {
+            com.audio.pattern[i] = self.read_word(addr + i)?;
+        }
+
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
 
-        self.pc += 2;
+    fn op_pitch(&mut self, reg: inst::Nibble, com: &mut Communicator) -> Result<(), CPUError> {
+        com.audio.pitch = self.get_register(reg)?;
+
+        self.pc = self.pc.wrapping_add(2);
         Ok(())
     }
 
+    // Number of bytes to add to the PC to skip the next instruction
+    fn skip_size(&self) -> u16 {
+        match self.memory.read_dword(self.pc as usize + 2) {
+            Ok(next) if inst::Instruction::is_long(next) => 6,
+            _ => 4,
+        }
+    }
+
     fn unwrap_value(&self, val: inst::Value) -> Result<u8, CPUError> {
         match val {
             inst::Value::Register(reg) => self.get_register(reg),
@@ -346,34 +821,185 @@ impl CPU {
     fn read_word(&mut self, addr: usize) -> Result<u8, CPUError> {
         self.memory
             .read_word(addr)
-            .map_err(|err| CPUError::MemoryError(err))
+            .map_err(CPUError::MemoryError)
     }
 
     fn write_word(&mut self, addr: usize, b: u8) -> Result<(), CPUError> {
+        let previous = self.memory.read_word(addr);
         self.memory
             .write_word(addr, b)
-            .map_err(|err| CPUError::MemoryError(err))
+            .map_err(CPUError::MemoryError)?;
+        if let (Some(ref mut journal), Ok(previous)) = (self.journal.as_mut(), previous) {
+            journal.write_memory(addr, previous);
+        }
+        Ok(())
     }
 
     fn read_dword(&mut self, addr: usize) -> Result<u16, CPUError> {
         self.memory
             .read_dword(addr)
-            .map_err(|err| CPUError::MemoryError(err))
+            .map_err(CPUError::MemoryError)
     }
 
     fn set_pixel(&mut self,
                  x: usize,
                  y: usize,
                  pixel: bool,
+                 plane: u8,
                  video_com: &mut VideoCommunicator)
                  -> bool {
+        if self.quirks.sprite_mode == SpriteMode::Clip &&
+           (x >= video_com.width || y >= video_com.height) {
+            return false;
+        }
+
         // Wrap the pixels around the screen
         let x = x % video_com.width;
         let y = y % video_com.height;
 
         let idx = video_com.width * y + x;
-        let collision = video_com.display[idx] != false && pixel;
-        video_com.display[idx] = video_com.display[idx] ^ pixel;
+        if !pixel {
+            return false;
+        }
+        if let Some(ref mut journal) = self.journal {
+            journal.write_pixel(idx, video_com.display[idx]);
+        }
+        let collision = video_com.display[idx] & plane != 0;
+        video_com.display[idx] ^= plane;
         collision
     }
-}
\ No newline at end of file
+}
+
+// Registers from first to last inclusive, in descending order if last < first.
+fn register_range(first: inst::Nibble,
+                  last: inst::Nibble)
+                  -> Box<dyn Iterator<Item = inst::Nibble>> {
+    if first <= last {
+        Box::new(first..last + 1)
+    } else {
+        Box::new((last..first + 1).rev())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CPU, Quirks, SpriteMode};
+    use memory;
+    use com::Communicator;
+
+    // A CPU running DRW V0, V1, 1 on the sprite 0xFF that follows it.
+    fn sprite_cpu(sprite_mode: SpriteMode) -> CPU {
+        let mut cpu = CPU::new();
+        cpu.set_quirks(Quirks { sprite_mode, ..Quirks::legacy() });
+        cpu.load_rom(vec![0xD0, 0x11, 0xFF]);
+        cpu
+    }
+
+    fn draw(cpu: &mut CPU, com: &mut Communicator, x: u8, y: u8) {
+        let mut registers = cpu.registers();
+        registers.v[0] = x;
+        registers.v[1] = y;
+        registers.i = 0x202;
+        registers.pc = 0x200;
+        cpu.set_registers(&registers);
+        cpu.step(com).unwrap();
+    }
+
+    // Columns lit on a line of the display.
+    fn lit(com: &Communicator, y: usize) -> Vec<usize> {
+        let width = com.video.width;
+        (0..width).filter(|x| com.video.display[y * width + x] != 0).collect()
+    }
+
+    #[test]
+    fn drw_sets_vf_on_collision() {
+        let mut cpu = sprite_cpu(SpriteMode::Wrap);
+        let mut com = Communicator::new();
+        draw(&mut cpu, &mut com, 0, 0);
+        assert_eq!(cpu.registers().v[15], 0);
+        assert_eq!(lit(&com, 0), (0..8).collect::<Vec<_>>());
+
+        draw(&mut cpu, &mut com, 4, 0);
+        assert_eq!(cpu.registers().v[15], 1);
+        assert_eq!(lit(&com, 0), vec![0, 1, 2, 3, 8, 9, 10, 11]);
+
+        draw(&mut cpu, &mut com, 0, 1);
+        assert_eq!(cpu.registers().v[15], 0);
+    }
+
+    #[test]
+    fn drw_advances_pc() {
+        let mut cpu = sprite_cpu(SpriteMode::Wrap);
+        let mut com = Communicator::new();
+        draw(&mut cpu, &mut com, 0, 0);
+        assert_eq!(cpu.registers().pc, 0x202);
+    }
+
+    #[test]
+    fn drw_wraps_sprite_past_the_edge() {
+        let mut cpu = sprite_cpu(SpriteMode::Wrap);
+        let mut com = Communicator::new();
+        draw(&mut cpu, &mut com, 60, 5);
+        assert_eq!(lit(&com, 5), vec![0, 1, 2, 3, 60, 61, 62, 63]);
+    }
+
+    #[test]
+    fn drw_clips_sprite_past_the_edge() {
+        let mut cpu = sprite_cpu(SpriteMode::Clip);
+        let mut com = Communicator::new();
+        draw(&mut cpu, &mut com, 60, 5);
+        assert_eq!(lit(&com, 5), vec![60, 61, 62, 63]);
+        assert_eq!(cpu.registers().v[15], 0);
+    }
+
+    #[test]
+    fn drw_wraps_origin_in_both_modes() {
+        for mode in [SpriteMode::Wrap, SpriteMode::Clip].iter() {
+            let mut cpu = sprite_cpu(*mode);
+            let mut com = Communicator::new();
+            draw(&mut cpu, &mut com, 64 + 2, 32 + 1);
+            assert_eq!(lit(&com, 1), (2..10).collect::<Vec<_>>());
+            assert!(com.video.display[..64].iter().all(|pixel| *pixel == 0));
+        }
+    }
+
+    #[test]
+    fn load_store_increments_i_per_profile() {
+        let profiles = [(Quirks::legacy(), 0x300),
+                        (Quirks::chip48(), 0x302),
+                        (Quirks::cosmac_vip(), 0x303)];
+        for &(quirks, i) in profiles.iter() {
+            let mut cpu = CPU::new();
+            cpu.set_quirks(quirks);
+            // LD [I], V2
+            cpu.load_rom(vec![0xF2, 0x55]);
+            let mut registers = cpu.registers();
+            registers.i = 0x300;
+            cpu.set_registers(&registers);
+            cpu.step(&mut Communicator::new()).unwrap();
+            assert_eq!(cpu.registers().i, i);
+        }
+    }
+
+    #[test]
+    fn pc_wraps_at_the_end_of_memory() {
+        let mut cpu = CPU::with_ram_size(memory::XO_RAM_SIZE);
+        let mut com = Communicator::new();
+        // CLS, then CALL 0x300 at the last address
+        cpu.memory_mut().write_word(0xFFFE, 0x00).unwrap();
+        cpu.memory_mut().write_word(0xFFFF, 0xE0).unwrap();
+        let mut registers = cpu.registers();
+        registers.pc = 0xFFFE;
+        cpu.set_registers(&registers);
+        cpu.step(&mut com).unwrap();
+        assert_eq!(cpu.registers().pc, 0);
+
+        cpu.memory_mut().write_word(0xFFFF, 0x00).unwrap();
+        cpu.memory_mut().write_word(0xFFFE, 0x23).unwrap();
+        registers.pc = 0xFFFE;
+        cpu.set_registers(&registers);
+        cpu.step(&mut com).unwrap();
+        assert_eq!(cpu.registers().pc, 0x300);
+        assert_eq!(cpu.registers().stack[0], 0);
+    }
+}
@@ -0,0 +1,100 @@
+use super::SpriteMode;
+
+// Behaviors that differ between CHIP-8 interpreters.
+#[derive(Clone,Copy,PartialEq)]
+pub struct Quirks {
+    // 8XY6/8XYE shift VY and store the result in VX, instead of shifting VX in place.
+    pub shift_vy: bool,
+    // FX55/FX65 leave I pointing right after the last register saved or loaded.
+    pub load_store_increment_i: bool,
+    // ...but only by X, leaving I on the last register (CHIP-48).
+    pub load_store_increment_i_by_x: bool,
+    // 8XY1/8XY2/8XY3 reset VF to 0.
+    pub logic_reset_vf: bool,
+    // BNNN jumps to NNN + VX (X being the highest nibble of NNN) instead of NNN + V0.
+    pub jump_vx: bool,
+    // DRW waits for the next 60 Hz tick (vertical blank) before executing anything else.
+    pub display_wait: bool,
+    pub sprite_mode: SpriteMode,
+}
+
+impl Quirks {
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_vy: true,
+            load_store_increment_i: true,
+            load_store_increment_i_by_x: false,
+            logic_reset_vf: true,
+            jump_vx: false,
+            display_wait: true,
+            sprite_mode: SpriteMode::Clip,
+        }
+    }
+
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_vy: false,
+            load_store_increment_i: true,
+            load_store_increment_i_by_x: true,
+            logic_reset_vf: false,
+            jump_vx: true,
+            display_wait: false,
+            sprite_mode: SpriteMode::Clip,
+        }
+    }
+
+    pub fn schip() -> Quirks {
+        Quirks {
+            shift_vy: false,
+            load_store_increment_i: false,
+            load_store_increment_i_by_x: false,
+            logic_reset_vf: false,
+            jump_vx: true,
+            display_wait: false,
+            sprite_mode: SpriteMode::Clip,
+        }
+    }
+
+    // What most recent ROMs (and Octo) expect.
+    pub fn modern() -> Quirks {
+        Quirks {
+            shift_vy: true,
+            load_store_increment_i: true,
+            load_store_increment_i_by_x: false,
+            logic_reset_vf: false,
+            jump_vx: false,
+            display_wait: false,
+            sprite_mode: SpriteMode::Wrap,
+        }
+    }
+
+    // The original behavior of this interpreter.
+    pub fn legacy() -> Quirks {
+        Quirks {
+            shift_vy: false,
+            load_store_increment_i: false,
+            load_store_increment_i_by_x: false,
+            logic_reset_vf: false,
+            jump_vx: false,
+            display_wait: false,
+            sprite_mode: SpriteMode::Wrap,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match name {
+            "vip" | "cosmac-vip" => Some(Quirks::cosmac_vip()),
+            "chip48" => Some(Quirks::chip48()),
+            "schip" => Some(Quirks::schip()),
+            "modern" => Some(Quirks::modern()),
+            "legacy" => Some(Quirks::legacy()),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks::legacy()
+    }
+}
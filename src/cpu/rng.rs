@@ -0,0 +1,84 @@
+use memory::Memory;
+
+// Algorithm behind the RND instruction.
+#[derive(Clone,Copy,PartialEq,Default)]
+pub enum RndMode {
+    // Xorshift64*, good quality random numbers
+    #[default]
+    Xorshift,
+    // The COSMAC VIP interpreter routine, for accuracy comparisons
+    CosmacVip,
+}
+
+impl RndMode {
+    pub fn from_name(name: &str) -> Option<RndMode> {
+        match name {
+            "xorshift" => Some(RndMode::Xorshift),
+            "vip" | "cosmac-vip" => Some(RndMode::CosmacVip),
+            _ => None,
+        }
+    }
+}
+
+// Random number generator owned by the CPU, so that its state is part of the machine state and
+// a given seed always gives the same numbers.
+pub struct Rng {
+    pub state: u64,
+    mode: RndMode,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        let mut rng = Rng {
+            state: 0,
+            mode: RndMode::default(),
+        };
+        rng.seed(seed);
+        rng
+    }
+
+    pub fn seed(&mut self, seed: u64) {
+        // Xorshift gets stuck on a zero state
+        self.state = if seed == 0 && self.mode == RndMode::Xorshift {
+            0x9E37_79B9_7F4A_7C15
+        } else {
+            seed
+        };
+    }
+
+    pub fn set_mode(&mut self, mode: RndMode) {
+        self.mode = mode;
+        let state = self.state;
+        self.seed(state);
+    }
+
+    pub fn next_byte(&mut self, memory: &Memory) -> u8 {
+        match self.mode {
+            RndMode::Xorshift => self.xorshift(),
+            RndMode::CosmacVip => self.cosmac_vip(memory),
+        }
+    }
+
+    fn xorshift(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+
+    // The VIP keeps a 16-bit seed in R9. RND increments it, adds its high byte to the byte of
+    // the interpreter page (0x100-0x1FF) its low byte points to, then adds that sum rotated
+    // right through the carry to itself. The result is the random number and the new high byte
+    // of the seed. The interpreter page is read from the emulated memory, which only holds the
+    // VIP interpreter if it was loaded there.
+    fn cosmac_vip(&mut self, memory: &Memory) -> u8 {
+        let r9 = (self.state as u16).wrapping_add(1);
+        let byte = memory.read_word(0x100 | (r9 & 0xFF) as usize).unwrap_or(0);
+        let sum = byte as u16 + (r9 >> 8);
+        let (d, carry) = (sum as u8, sum > 0xFF);
+        let rotated = d >> 1 | if carry { 0x80 } else { 0 };
+        let result = rotated.wrapping_add(d);
+        self.state = ((result as u16) << 8 | r9 & 0xFF) as u64;
+        result
+    }
+}
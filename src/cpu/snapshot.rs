@@ -0,0 +1,312 @@
+// Save states: the whole machine (CPU, memory, timers, random generator, display and audio
+// pattern) in a binary format. Everything is big-endian, after a magic header and a format
+// version:
+//
+//     "TW-CHIP8" version:u16
+//     V0-VF:u8[16] I:u16 PC:u16 SP:u8 stack:u16[16] RPL:u8[16]
+//     waiting_key:u8 waiting_vblank:u8 running:u8 cycles:u64 frames:u64
+//     DT:u8 ST:u8 timer_elapsed:u32 rng:u64
+//     memory_size:u32 memory:u8[memory_size]
+//     width:u16 height:u16 planes:u8 display:u8[width * height]
+//     audio_pattern:u8[16] pitch:u8
+//
+// The quirks aren't part of the state, they come from the options.
+
+use std::io;
+use std::fmt;
+use std::error::Error;
+
+use binary::{write_u16, write_u32, write_u64, read_u8, read_u16, read_u32, read_u64};
+use memory::{self, Memory};
+use com::Communicator;
+use com::audio::PATTERN_SIZE;
+use com::video::{self, VideoSignal, PLANE_COUNT};
+use super::{CPU, STACK_SIZE, V_REGISTER_COUNT, RPL_FLAG_COUNT};
+
+const MAGIC: &[u8; 8] = b"TW-CHIP8";
+const VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    IoError(io::Error),
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    InvalidState(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SnapshotError::IoError(ref err) => write!(f, "I/O error: {}", err),
+            SnapshotError::InvalidMagic => write!(f, "not a save state"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            SnapshotError::InvalidState(reason) => write!(f, "invalid save state: {}", reason),
+        }
+    }
+}
+
+impl Error for SnapshotError {
+    fn description(&self) -> &str {
+        match *self {
+            SnapshotError::IoError(_) => "I/O error",
+            SnapshotError::InvalidMagic => "not a save state",
+            SnapshotError::UnsupportedVersion(_) => "unsupported save state version",
+            SnapshotError::InvalidState(_) => "invalid save state",
+        }
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            SnapshotError::IoError(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> SnapshotError {
+        SnapshotError::IoError(err)
+    }
+}
+
+impl CPU {
+    // Write the state of the machine, the display and audio pattern being in the communicator.
+    pub fn save_state<W>(&self, com: &Communicator, out: &mut W) -> io::Result<()>
+        where W: io::Write
+    {
+        out.write_all(MAGIC)?;
+        write_u16(out, VERSION)?;
+
+        out.write_all(&self.v_registers)?;
+        write_u16(out, self.i_register)?;
+        write_u16(out, self.pc)?;
+        out.write_all(&[self.sp])?;
+        for addr in self.stack.iter() {
+            write_u16(out, *addr)?;
+        }
+        out.write_all(&self.rpl_flags)?;
+        out.write_all(&[self.waiting_key as u8, self.waiting_vblank as u8, self.running as u8])?;
+        write_u64(out, self.cycles)?;
+        write_u64(out, self.frames)?;
+
+        out.write_all(&[self.timers.delay, self.timers.sound])?;
+        write_u32(out, self.timers.elapsed)?;
+        write_u64(out, self.rng.state)?;
+
+        let ram = self.memory.ram();
+        write_u32(out, ram.len() as u32)?;
+        out.write_all(ram)?;
+
+        write_u16(out, com.video.width as u16)?;
+        write_u16(out, com.video.height as u16)?;
+        out.write_all(&[com.video.planes])?;
+        out.write_all(&com.video.display)?;
+
+        out.write_all(&com.audio.pattern)?;
+        out.write_all(&[com.audio.pitch])
+    }
+
+    // Restore a state written by save_state. Nothing is changed if it can't be read.
+    pub fn load_state<R>(&mut self, com: &mut Communicator, input: &mut R) -> Result<(), SnapshotError>
+        where R: io::Read
+    {
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(SnapshotError::InvalidMagic);
+        }
+        let version = read_u16(input)?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let mut v_registers = [0u8; V_REGISTER_COUNT];
+        input.read_exact(&mut v_registers)?;
+        let i_register = read_u16(input)?;
+        let pc = read_u16(input)?;
+        let sp = read_u8(input)?;
+        if sp as usize > STACK_SIZE {
+            return Err(SnapshotError::InvalidState("stack pointer out of the stack"));
+        }
+        let mut stack = [0u16; STACK_SIZE];
+        for addr in stack.iter_mut() {
+            *addr = read_u16(input)?;
+        }
+        let mut rpl_flags = [0u8; RPL_FLAG_COUNT];
+        input.read_exact(&mut rpl_flags)?;
+        let waiting_key = read_u8(input)? != 0;
+        let waiting_vblank = read_u8(input)? != 0;
+        let running = read_u8(input)? != 0;
+        let cycles = read_u64(input)?;
+        let frames = read_u64(input)?;
+
+        let delay = read_u8(input)?;
+        let sound = read_u8(input)?;
+        let elapsed = read_u32(input)?;
+        let rng_state = read_u64(input)?;
+
+        let ram_size = read_u32(input)? as usize;
+        if !(memory::RAM_SIZE..=memory::XO_RAM_SIZE).contains(&ram_size) {
+            return Err(SnapshotError::InvalidState("unsupported memory size"));
+        }
+        let mut ram = vec![0u8; ram_size];
+        input.read_exact(&mut ram)?;
+
+        let width = read_u16(input)? as usize;
+        let height = read_u16(input)? as usize;
+        let valid_resolution = (width, height) ==
+                               (video::DEFAULT_DISPLAY_WIDTH, video::DEFAULT_DISPLAY_HEIGHT) ||
+                               (width, height) ==
+                               (video::HIRES_DISPLAY_WIDTH, video::HIRES_DISPLAY_HEIGHT);
+        if !valid_resolution {
+            return Err(SnapshotError::InvalidState("unsupported display resolution"));
+        }
+        let planes = read_u8(input)?;
+        let mut display = vec![0u8; width * height];
+        input.read_exact(&mut display)?;
+        if planes >= 1 << PLANE_COUNT || display.iter().any(|pixel| *pixel >= 1 << PLANE_COUNT) {
+            return Err(SnapshotError::InvalidState("unknown display plane"));
+        }
+
+        let mut pattern = [0u8; PATTERN_SIZE];
+        input.read_exact(&mut pattern)?;
+        let pitch = read_u8(input)?;
+
+        // Everything was read, it's now safe to replace the current state
+        self.v_registers = v_registers;
+        self.i_register = i_register;
+        self.pc = pc;
+        self.sp = sp;
+        self.stack = stack;
+        self.rpl_flags = rpl_flags;
+        self.waiting_key = waiting_key;
+        self.waiting_vblank = waiting_vblank;
+        self.running = running;
+        self.cycles = cycles;
+        self.frames = frames;
+        self.timers.delay = delay;
+        self.timers.sound = sound;
+        // Clamped like on a clock rate change, the state may come from a faster clock
+        self.timers.elapsed = elapsed.min(self.timers.clock_rate().saturating_sub(1));
+        self.rng.state = rng_state;
+        self.memory = Memory::from_ram(ram);
+
+        com.video.resize(width, height);
+        com.video.display = display;
+        com.video.planes = planes;
+        com.video.signal = VideoSignal::Refresh;
+        com.audio.pattern = pattern;
+        com.audio.pitch = pitch;
+        com.audio.beeping = sound > 0;
+        com.input.clear_released();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::SnapshotError;
+    use cpu::CPU;
+    use com::Communicator;
+
+    // Sets both timers, then draws a sprite at a random height forever
+    const ROM: [u8; 21] = [0x60, 0x0A, 0xF0, 0x15, 0xF0, 0x18, 0xC1, 0xFF, 0xA2, 0x10,
+                           0xD0, 0x15, 0x12, 0x06, 0x00, 0x00, 0xF0, 0x90, 0xF0, 0x90, 0xF0];
+
+    fn running_state() -> (CPU, Communicator, Vec<u8>) {
+        let mut cpu = CPU::new();
+        cpu.load_rom(ROM.to_vec());
+        let mut com = Communicator::new();
+        while cpu.frames() < 3 {
+            cpu.step(&mut com).unwrap();
+        }
+        let mut state = Vec::new();
+        cpu.save_state(&com, &mut state).unwrap();
+        (cpu, com, state)
+    }
+
+    fn load(state: &[u8]) -> (CPU, Communicator, Result<(), SnapshotError>) {
+        let mut cpu = CPU::new();
+        let mut com = Communicator::new();
+        let result = cpu.load_state(&mut com, &mut &state[..]);
+        (cpu, com, result)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut cpu, mut com, state) = running_state();
+        let (mut loaded, mut loaded_com, result) = load(&state);
+        result.unwrap();
+
+        assert_eq!(loaded_com.video.display, com.video.display);
+        assert_eq!(loaded.timers.delay, cpu.timers.delay);
+        assert_eq!(loaded.timers.sound, cpu.timers.sound);
+        assert_eq!(loaded.timers.elapsed, cpu.timers.elapsed);
+        assert_eq!(loaded.rng.state, cpu.rng.state);
+        let mut saved = Vec::new();
+        loaded.save_state(&loaded_com, &mut saved).unwrap();
+        assert_eq!(saved, state);
+
+        // Both machines keep running the same way
+        for _ in 0..100 {
+            cpu.step(&mut com).unwrap();
+            loaded.step(&mut loaded_com).unwrap();
+        }
+        let mut state = Vec::new();
+        cpu.save_state(&com, &mut state).unwrap();
+        let mut saved = Vec::new();
+        loaded.save_state(&loaded_com, &mut saved).unwrap();
+        assert_eq!(saved, state);
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let (_, _, mut state) = running_state();
+        state[0] = b'X';
+        let (cpu, _, result) = load(&state);
+        match result {
+            Err(SnapshotError::InvalidMagic) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(cpu.pc, 0x200);
+    }
+
+    #[test]
+    fn rejects_bad_version() {
+        let (_, _, mut state) = running_state();
+        state[8] = 0xFF;
+        match load(&state).2 {
+            Err(SnapshotError::UnsupportedVersion(0xFF01)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let (_, _, state) = running_state();
+        let (cpu, com, result) = load(&state[..state.len() - 1]);
+        match result {
+            Err(SnapshotError::IoError(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(cpu.pc, 0x200);
+        assert!(com.video.display.iter().all(|pixel| *pixel == 0));
+    }
+
+    #[test]
+    fn clamps_timer_elapsed() {
+        let (running, _, mut state) = running_state();
+        // After the header, registers, stack, flags, counters and timer values
+        let offset = 10 + 16 + 2 + 2 + 1 + 32 + 16 + 3 + 8 + 8 + 2;
+        assert_eq!(state[offset..offset + 4], running.timers.elapsed.to_be_bytes());
+        state[offset..offset + 4].copy_from_slice(&[0xFF; 4]);
+        let (mut cpu, mut com, result) = load(&state);
+        result.unwrap();
+        assert!(cpu.timers.elapsed < cpu.timers.clock_rate());
+        cpu.step(&mut com).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+pub const TIMER_FREQUENCY: u32 = 60;
+pub const DEFAULT_CLOCK_RATE: u32 = 540;
+
+// Delay and sound timers, decremented at 60 Hz of emulated time.
+// Emulated time is measured in instructions, or machine cycles with the VIP timing: with a
+// clock rate of N per second, the timers tick once every N / 60 (fractions carried over).
+pub struct Timers {
+    pub delay: u8,
+    pub sound: u8,
+    clock_rate: u32,
+    pub elapsed: u32,
+}
+
+impl Timers {
+    pub fn new(clock_rate: u32) -> Timers {
+        Timers {
+            delay: 0u8,
+            sound: 0u8,
+            clock_rate,
+            elapsed: 0u32,
+        }
+    }
+
+    pub fn clock_rate(&self) -> u32 {
+        self.clock_rate
+    }
+
+    pub fn set_clock_rate(&mut self, clock_rate: u32) {
+        self.clock_rate = clock_rate;
+        // A slower clock would otherwise be past its next tick
+        self.elapsed = self.elapsed.min(clock_rate.saturating_sub(1));
+    }
+
+    // Advances emulated time by the given instructions or cycles, returns how many times the
+    // timers ticked.
+    pub fn step(&mut self, units: u32) -> u32 {
+        // Counted in 64 bits, a clock rate close to the u32 limit would otherwise overflow
+        let mut elapsed = self.elapsed as u64 + units as u64 * TIMER_FREQUENCY as u64;
+        let mut ticks = 0;
+        while elapsed >= self.clock_rate as u64 {
+            elapsed -= self.clock_rate as u64;
+            self.tick();
+            ticks += 1;
+        }
+        self.elapsed = elapsed as u32;
+        ticks
+    }
+
+    // Instructions or cycles left until the next tick, rounded up.
+    pub fn until_tick(&self) -> u32 {
+        self.clock_rate.saturating_sub(self.elapsed).div_ceil(TIMER_FREQUENCY)
+    }
+
+    fn tick(&mut self) {
+        self.delay = self.delay.saturating_sub(1);
+        self.sound = self.sound.saturating_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Timers;
+
+    #[test]
+    fn until_tick_after_slowing_down() {
+        let mut timers = Timers::new(6000);
+        timers.step(90);
+        assert_eq!(timers.until_tick(), 10);
+        timers.set_clock_rate(600);
+        assert_eq!(timers.until_tick(), 1);
+        assert_eq!(timers.step(1), 1);
+
+        timers.elapsed = 1000;
+        assert_eq!(timers.until_tick(), 0);
+    }
+
+    #[test]
+    fn step_near_the_maximum_clock_rate() {
+        let mut timers = Timers::new(u32::MAX);
+        timers.elapsed = u32::MAX - 1;
+        timers.delay = 2;
+        assert_eq!(timers.step(1), 1);
+        assert_eq!(timers.elapsed, 59);
+        assert_eq!(timers.delay, 1);
+        assert_eq!(timers.until_tick(), (u32::MAX - 59).div_ceil(60));
+    }
+}
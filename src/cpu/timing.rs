@@ -0,0 +1,93 @@
+// Timing models: how much emulated time each instruction takes.
+//
+// The COSMAC VIP costs come from Laurence Scotford's analysis of the VIP interpreter, in
+// machine cycles (8 clock cycles of the 1.76 MHz CDP1802). Each instruction costs the fetch
+// and decode of the interpreter loop plus its own routine. The data-dependent parts are
+// approximated: the sprite routine is charged per row depending only on its alignment, and
+// the instructions the VIP doesn't have (SUPER-CHIP, XO-CHIP) get a flat cost.
+
+use super::CPU;
+use super::inst::{Instruction, Value};
+
+// Machine cycles in a 60 Hz frame, minus those taken by the display DMA and interrupt routine.
+pub const VIP_CYCLES_PER_FRAME: u32 = 3668 - 1096;
+
+// Fetch and decode of an instruction by the interpreter loop.
+const VIP_FETCH_CYCLES: u32 = 40;
+
+// Extra cost of a skip instruction when the next instruction is skipped.
+pub const VIP_SKIP_CYCLES: u32 = 4;
+
+// Cost of the instructions the VIP interpreter doesn't have.
+const VIP_UNKNOWN_CYCLES: u32 = 20;
+
+#[derive(Clone,Copy,PartialEq,Default)]
+pub enum Timing {
+    // Every instruction takes the same time, the clock rate being in instructions per second
+    #[default]
+    Flat,
+    // Every instruction takes its machine cycle cost on the COSMAC VIP, the clock rate being
+    // ignored
+    CosmacVip,
+}
+
+impl Timing {
+    pub fn from_name(name: &str) -> Option<Timing> {
+        match name {
+            "flat" => Some(Timing::Flat),
+            "vip" | "cosmac-vip" => Some(Timing::CosmacVip),
+            _ => None,
+        }
+    }
+}
+
+impl CPU {
+    // Machine cycles taken by the instruction on the VIP, from the state before executing it.
+    // The extra cost of a skip is added by the caller, once it is known whether it happened.
+    pub fn vip_cycles(&self, inst: &Instruction) -> u32 {
+        use super::inst::Instruction::*;
+        let cycles = match *inst {
+            CLS => 3078,
+            RET => 10,
+            JP(_) => 12,
+            CALL(_) => 26,
+            SE(_, Value::Byte(_)) |
+            SNE(_, Value::Byte(_)) => 10,
+            SE(_, Value::Register(_)) |
+            SNE(_, Value::Register(_)) => 14,
+            LD(_, Value::Byte(_)) => 6,
+            ADD(_, Value::Byte(_)) => 10,
+            LD(_, Value::Register(_)) => 12,
+            ADD(_, Value::Register(_)) |
+            OR(_, _) | AND(_, _) | XOR(_, _) | SUB(_, _) | SHR(_, _) | SUBN(_, _) | SHL(_, _) => 44,
+            LDI(_) => 12,
+            JPO(_) => 22,
+            RND(_, _) => 36,
+            DRW(x_reg, _, size) => {
+                // The sprite is drawn once the next frame starts, a misaligned one having to be
+                // shifted across two bytes of the display
+                let aligned = self.v_registers[x_reg as usize].is_multiple_of(8);
+                let rows = if size == 0 { 16 } else { size as u32 };
+                self.timers.until_tick() + 26 + rows * if aligned { 34 } else { 68 }
+            }
+            SKP(_) | SKNP(_) => 14,
+            LDDT(_) | LDK(_) | LDSDT(_) | LDSST(_) => 10,
+            ADDI(_) => 16,
+            LDF(_) => 20,
+            LDB(reg) => {
+                // The digits are computed by repeated subtractions
+                let value = self.v_registers[reg as usize];
+                84 + 16 * (value / 100 + value / 10 % 10 + value % 10) as u32
+            }
+            LDSBLK(reg) | LDBLK(reg) => 14 + 14 * (reg as u32 + 1),
+            _ => VIP_UNKNOWN_CYCLES,
+        };
+        VIP_FETCH_CYCLES + cycles
+    }
+}
+
+// Whether the instruction may skip the next one.
+pub fn is_skip(inst: &Instruction) -> bool {
+    use super::inst::Instruction::*;
+    matches!(*inst, SE(_, _) | SNE(_, _) | SKP(_) | SKNP(_))
+}
@@ -0,0 +1,141 @@
+// Undoing steps: everything a step may change is saved before executing it, except for the
+// memory and the display which are too big for that. Their bytes are saved as they are written
+// instead, along with the whole display before the instructions that rewrite it (clears,
+// scrolls and resolution changes).
+
+use std::mem;
+
+use com::Communicator;
+use com::audio::PATTERN_SIZE;
+use com::video::VideoSignal;
+use super::{CPU, Registers, RPL_FLAG_COUNT};
+use super::inst::Instruction;
+
+// Memory and display bytes written during a step, with their previous value.
+pub struct Journal {
+    memory: Vec<(usize, u8)>,
+    pixels: Vec<(usize, u8)>,
+    // Display and its resolution
+    display: Option<(Vec<u8>, usize, usize)>,
+}
+
+impl Journal {
+    fn new() -> Journal {
+        Journal {
+            memory: Vec::new(),
+            pixels: Vec::new(),
+            display: None,
+        }
+    }
+
+    pub fn write_memory(&mut self, addr: usize, previous: u8) {
+        self.memory.push((addr, previous));
+    }
+
+    pub fn write_pixel(&mut self, idx: usize, previous: u8) {
+        self.pixels.push((idx, previous));
+    }
+
+    // Save the whole display if the instruction is about to rewrite it.
+    pub fn before(&mut self, inst: &Instruction, com: &Communicator) {
+        use super::inst::Instruction::*;
+        match *inst {
+            CLS | SCD(_) | SCU(_) | SCR | SCL | LOW | HIGH => {
+                self.display = Some((com.video.display.clone(), com.video.width, com.video.height));
+            }
+            _ => (),
+        }
+    }
+}
+
+// State of the machine before a step, to go back to it.
+pub struct Undo {
+    registers: Registers,
+    rpl_flags: [u8; RPL_FLAG_COUNT],
+    waiting_key: bool,
+    waiting_vblank: bool,
+    running: bool,
+    cycles: u64,
+    frames: u64,
+    timer_elapsed: u32,
+    rng_state: u64,
+    planes: u8,
+    audio_pattern: [u8; PATTERN_SIZE],
+    pitch: u8,
+    released: u16,
+    journal: Journal,
+}
+
+impl Undo {
+    // Address of the instruction the step executed.
+    pub fn pc(&self) -> u16 {
+        self.registers.pc
+    }
+
+    // Memory used by the undo, in bytes.
+    pub fn size(&self) -> usize {
+        let journal = &self.journal;
+        mem::size_of::<Undo>() +
+        (journal.memory.len() + journal.pixels.len()) * mem::size_of::<(usize, u8)>() +
+        journal.display.as_ref().map_or(0, |(display, _, _)| display.len())
+    }
+}
+
+impl CPU {
+    // Execute a step, also returning what is needed to undo it, even if it failed.
+    pub fn step_with_undo(&mut self, com: &mut Communicator) -> (Undo, Result<(), super::CPUError>) {
+        let mut undo = Undo {
+            registers: self.registers(),
+            rpl_flags: self.rpl_flags,
+            waiting_key: self.waiting_key,
+            waiting_vblank: self.waiting_vblank,
+            running: self.running,
+            cycles: self.cycles,
+            frames: self.frames,
+            timer_elapsed: self.timers.elapsed,
+            rng_state: self.rng.state,
+            planes: com.video.planes,
+            audio_pattern: com.audio.pattern,
+            pitch: com.audio.pitch,
+            released: com.input.released(),
+            journal: Journal::new(),
+        };
+
+        self.journal = Some(Journal::new());
+        let result = self.step(com);
+        undo.journal = self.journal.take().unwrap();
+        (undo, result)
+    }
+
+    // Go back to the state before the step, the keys pressed being left as they are.
+    pub fn undo(&mut self, com: &mut Communicator, undo: Undo) {
+        self.set_registers(&undo.registers);
+        self.rpl_flags = undo.rpl_flags;
+        self.waiting_key = undo.waiting_key;
+        self.waiting_vblank = undo.waiting_vblank;
+        self.running = undo.running;
+        self.cycles = undo.cycles;
+        self.frames = undo.frames;
+        self.timers.elapsed = undo.timer_elapsed;
+        self.rng.state = undo.rng_state;
+
+        // Later writes are undone first, in case an address was written twice
+        for &(addr, previous) in undo.journal.memory.iter().rev() {
+            // Only successful writes were saved, so this can't fail either
+            let _ = self.memory.write_word(addr, previous);
+        }
+        if let Some((display, width, height)) = undo.journal.display {
+            com.video.resize(width, height);
+            com.video.display = display;
+        }
+        for &(idx, previous) in undo.journal.pixels.iter().rev() {
+            com.video.display[idx] = previous;
+        }
+        com.video.planes = undo.planes;
+        com.video.signal = VideoSignal::Refresh;
+        com.audio.pattern = undo.audio_pattern;
+        com.audio.pitch = undo.pitch;
+        com.audio.beeping = self.timers.sound > 0;
+        com.input.set_released(undo.released);
+    }
+}
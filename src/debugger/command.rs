@@ -0,0 +1,194 @@
+use std::fmt;
+use std::convert::TryFrom;
+
+pub enum Breakpoint {
+    Address(u16),
+    // Matches the opcodes for which (opcode & mask) == value
+    Opcode { value: u16, mask: u16 },
+}
+
+impl Breakpoint {
+    pub fn hit(&self, pc: u16, opcode: u16) -> bool {
+        match *self {
+            Breakpoint::Address(addr) => addr == pc,
+            Breakpoint::Opcode { value, mask } => opcode & mask == value,
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Breakpoint::Address(addr) => write!(f, "at {:#X}", addr),
+            Breakpoint::Opcode { value, mask } => {
+                write!(f, "on opcode ")?;
+                for nibble in 0..4 {
+                    let shift = 12 - nibble * 4;
+                    if (mask >> shift) & 0xF == 0 {
+                        write!(f, "?")?;
+                    } else {
+                        write!(f, "{:X}", (value >> shift) & 0xF)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Clone,Copy)]
+pub enum Command {
+    Step(u64),
+    Continue,
+    ReverseStep(u64),
+    ReverseContinue,
+    Pause,
+    Break(u16),
+    BreakOpcode(u16, u16),
+    Delete(Option<usize>),
+    Breakpoints,
+    Registers,
+    Memory(u16, u16),
+    Disassemble(Option<u16>, u16),
+    Help,
+    Quit,
+}
+
+pub static HELP: &str = "\
+step, s [N]            execute N instructions (default: 1)
+continue, c            run until a breakpoint is hit
+rstep, rs [N]          undo the last N instructions (default: 1)
+rcontinue, rc          run backwards until a breakpoint is hit
+pause                  interrupt the execution
+break, b ADDR          break when the PC reaches ADDR
+obreak, ob PATTERN     break on an opcode matching PATTERN, 4 hex digits where any other
+                       character is a wildcard (e.g. D??F or 8xy6)
+delete, d [N]          delete breakpoint N, or all breakpoints
+info, i                list breakpoints
+regs, r                print the registers, I, SP, timers and stack
+mem, x ADDR [LEN]      hexdump LEN bytes of memory from ADDR (default: 64)
+dis, l [ADDR] [N]      disassemble N instructions from ADDR (default: around the PC)
+help, h                print this help
+quit, q                stop the emulator
+Numbers are decimal, or hexadecimal when prefixed with 0x. An empty line repeats the last
+command.";
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, &'static str> {
+        let mut words = line.split_whitespace();
+        let name = match words.next() {
+            Some(name) => name,
+            None => return Err("empty command"),
+        };
+        let args: Vec<&str> = words.collect();
+
+        let command = match name {
+            "step" | "s" => Command::Step(optional_number(args.first())?.unwrap_or(1)),
+            "continue" | "c" => Command::Continue,
+            "rstep" | "rs" => Command::ReverseStep(optional_number(args.first())?.unwrap_or(1)),
+            "rcontinue" | "rc" => Command::ReverseContinue,
+            "pause" => Command::Pause,
+            "break" | "b" => Command::Break(required_address(args.first())?),
+            "obreak" | "ob" => {
+                let (value, mask) = parse_pattern(args.first())?;
+                Command::BreakOpcode(value, mask)
+            }
+            "delete" | "d" => Command::Delete(optional_number(args.first())?.map(|n| n as usize)),
+            "info" | "i" => Command::Breakpoints,
+            "regs" | "r" => Command::Registers,
+            "mem" | "x" => {
+                let len = optional_number(args.get(1))?.unwrap_or(64);
+                Command::Memory(required_address(args.first())?,
+                                narrow(len, "the length must be at most 0xFFFF")?)
+            }
+            "dis" | "l" => {
+                let addr = match optional_number(args.first())? {
+                    Some(addr) => Some(narrow(addr, ADDRESS_RANGE)?),
+                    None => None,
+                };
+                let count = optional_number(args.get(1))?.unwrap_or(10);
+                Command::Disassemble(addr, narrow(count, "the count must be at most 0xFFFF")?)
+            }
+            "help" | "h" => Command::Help,
+            "quit" | "q" => Command::Quit,
+            _ => return Err("unknown command, try help"),
+        };
+        Ok(command)
+    }
+}
+
+const ADDRESS_RANGE: &str = "the address must be at most 0xFFFF";
+
+// Addresses, lengths and counts are 16-bit, larger numbers are rejected rather than truncated.
+fn narrow(n: u64, error: &'static str) -> Result<u16, &'static str> {
+    u16::try_from(n).map_err(|_| error)
+}
+
+fn required_address(s: Option<&&str>) -> Result<u16, &'static str> {
+    narrow(required_number(s)?, ADDRESS_RANGE)
+}
+
+fn parse_number(s: &str) -> Result<u64, &'static str> {
+    let result = if s.starts_with("0x") || s.starts_with("0X") {
+        u64::from_str_radix(&s[2..], 16)
+    } else {
+        s.parse()
+    };
+    result.map_err(|_| "invalid number")
+}
+
+fn optional_number(s: Option<&&str>) -> Result<Option<u64>, &'static str> {
+    match s {
+        Some(s) => parse_number(s).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn required_number(s: Option<&&str>) -> Result<u64, &'static str> {
+    match s {
+        Some(s) => parse_number(s),
+        None => Err("missing argument"),
+    }
+}
+
+fn parse_pattern(s: Option<&&str>) -> Result<(u16, u16), &'static str> {
+    let s = match s {
+        Some(s) if s.len() == 4 => s,
+        _ => return Err("the opcode pattern must be 4 characters long"),
+    };
+
+    let mut value = 0u16;
+    let mut mask = 0u16;
+    for c in s.chars() {
+        value <<= 4;
+        mask <<= 4;
+        if let Some(digit) = c.to_digit(16) {
+            value |= digit as u16;
+            mask |= 0xF;
+        }
+    }
+    Ok((value, mask))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Command;
+
+    #[test]
+    fn out_of_range_numbers() {
+        match Command::parse("break 0xFFFF") {
+            Ok(Command::Break(0xFFFF)) => {},
+            _ => panic!("0xFFFF is an address"),
+        }
+        assert_eq!(Command::parse("break 0x10200").err(),
+                   Some("the address must be at most 0xFFFF"));
+        assert_eq!(Command::parse("mem 0x10000").err(),
+                   Some("the address must be at most 0xFFFF"));
+        assert_eq!(Command::parse("mem 0x200 65536").err(),
+                   Some("the length must be at most 0xFFFF"));
+        assert_eq!(Command::parse("dis 0x10000").err(),
+                   Some("the address must be at most 0xFFFF"));
+        assert_eq!(Command::parse("dis 0x200 0x10000").err(),
+                   Some("the count must be at most 0xFFFF"));
+    }
+}
@@ -0,0 +1,666 @@
+// Stub for the GDB Remote Serial Protocol, so that ROMs can be debugged from GDB (or any other
+// client speaking the protocol) with `target remote localhost:PORT`.
+//
+// The registers are described to the client through a target description (qXfer), they are
+// sent in big-endian order like the rest of the machine, so `set endian big` is needed on the
+// client side.
+
+use std::io;
+use std::io::prelude::*;
+use std::cmp;
+use std::str;
+use std::thread;
+use std::time::Duration;
+use std::collections::BTreeSet;
+use std::net::{TcpListener, TcpStream};
+
+use com::Communicator;
+use cpu::{CPU, CPUError, Fault, Registers};
+use super::{Controller, State};
+use super::history::History;
+
+// Size of the registers in the order they are numbered by the target description.
+const REGISTER_SIZES: [usize; 21] = [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 1, 1, 1];
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.tw-chip8.core">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+const TARGET_XML_QUERY: &str = "Xfer:features:read:target.xml:";
+
+// Largest packet accepted from and sent to the client, as advertised by qSupported.
+const PACKET_SIZE: usize = 0x4000;
+
+// Signals reported to the client when the execution stops.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+pub struct GdbStub {
+    // None once the client is gone, the CPU then runs freely.
+    stream: Option<TcpStream>,
+    received: Vec<u8>,
+    breakpoints: BTreeSet<u16>,
+    history: History,
+    state: State,
+    quit: bool,
+}
+
+impl GdbStub {
+    // Wait for a client to connect on the given local port.
+    pub fn listen(port: u16) -> io::Result<GdbStub> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        info!("Waiting for GDB on port {}.", port);
+        GdbStub::accept(&listener)
+    }
+
+    fn accept(listener: &TcpListener) -> io::Result<GdbStub> {
+        let (stream, addr) = listener.accept()?;
+        info!("GDB connected from {}.", addr);
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+
+        Ok(GdbStub {
+               stream: Some(stream),
+               received: Vec::new(),
+               breakpoints: BTreeSet::new(),
+               history: History::new(),
+               state: State::Paused,
+               quit: false,
+           })
+    }
+
+    // Handle the packets and breakpoints, returns true if the CPU may execute its next step.
+    fn poll(&mut self, cpu: &mut CPU) -> bool {
+        if self.stream.is_none() {
+            return true;
+        }
+
+        let interrupted = self.receive();
+        if let State::Running { steps, first_step, reverse } = self.state {
+            if interrupted {
+                self.stop(SIGINT);
+            } else if steps == Some(0) ||
+                      (!first_step && self.breakpoints.contains(&cpu.registers().pc)) {
+                self.stop(SIGTRAP);
+            } else {
+                self.state = State::Running {
+                    steps: steps.map(|steps| steps - 1),
+                    first_step: false,
+                    reverse,
+                };
+                return true;
+            }
+        }
+
+        let mut handled = false;
+        while let Some(packet) = self.next_packet() {
+            handled = true;
+            self.handle(&packet, cpu);
+            if self.stream.is_none() {
+                return true;
+            }
+        }
+        if !handled {
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    // Read whatever the client sent, returns true if it asked for an interruption.
+    fn receive(&mut self) -> bool {
+        let mut buffer = [0u8; 4096];
+        loop {
+            let result = match self.stream {
+                Some(ref mut stream) => stream.read(&mut buffer),
+                None => return false,
+            };
+            match result {
+                Ok(0) => {
+                    info!("GDB disconnected.");
+                    self.detach();
+                    return false;
+                }
+                Ok(len) => self.received.extend_from_slice(&buffer[..len]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    warn!("Can't read from GDB: {}.", e);
+                    self.detach();
+                    return false;
+                }
+            }
+        }
+
+        match self.received.iter().position(|b| *b == 0x03) {
+            Some(idx) => {
+                self.received.remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Extract the next complete packet, acknowledging it.
+    fn next_packet(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let start = match self.received.iter().position(|b| *b == b'$') {
+                Some(start) => start,
+                None => {
+                    // Only acknowledgements and stray bytes, nothing to keep
+                    self.received.clear();
+                    return None;
+                }
+            };
+            let end = match self.received[start..].iter().position(|b| *b == b'#') {
+                Some(end) if start + end + 2 < self.received.len() => start + end,
+                _ => return None,
+            };
+
+            let packet: Vec<u8> = self.received.drain(..end + 3).skip(start).collect();
+            let data = &packet[1..packet.len() - 3];
+            let checksum = parse_hex(&packet[packet.len() - 2..]);
+            if checksum != Some(checksum_of(data) as usize) {
+                self.send_raw(b"-");
+                continue;
+            }
+            self.send_raw(b"+");
+            return Some(data.to_vec());
+        }
+    }
+
+    // The packets are handled as bytes, the client may send anything.
+    fn handle(&mut self, packet: &[u8], cpu: &mut CPU) {
+        let (command, args) = packet.split_at(cmp::min(1, packet.len()));
+        let reply = match command {
+            b"?" => format!("S{:02X}", SIGTRAP),
+            b"q" => self.query(&String::from_utf8_lossy(args)),
+            b"H" => String::from("OK"),
+            b"g" => {
+                let registers = register_values(&cpu.registers());
+                (0..REGISTER_SIZES.len())
+                    .map(|reg| encode_register(reg, registers[reg]))
+                    .collect()
+            }
+            b"G" => {
+                let mut values = [0u16; 21];
+                let mut hex = args;
+                for reg in 0..REGISTER_SIZES.len() {
+                    let len = REGISTER_SIZES[reg] * 2;
+                    if hex.len() < len {
+                        break;
+                    }
+                    values[reg] = parse_hex(&hex[..len]).unwrap_or(0) as u16;
+                    hex = &hex[len..];
+                }
+                let mut registers = cpu.registers();
+                for (reg, value) in values.iter().enumerate() {
+                    set_register(&mut registers, reg, *value);
+                }
+                cpu.set_registers(&registers);
+                String::from("OK")
+            }
+            b"p" => {
+                match parse_hex(args) {
+                    Some(reg) if reg < REGISTER_SIZES.len() => {
+                        encode_register(reg, register_values(&cpu.registers())[reg])
+                    }
+                    _ => String::from("E01"),
+                }
+            }
+            b"P" => {
+                let mut parts = args.splitn(2, |b| *b == b'=');
+                let reg = parts.next().and_then(parse_hex);
+                let value = parts.next().and_then(parse_hex);
+                match (reg, value) {
+                    (Some(reg), Some(value)) if reg < REGISTER_SIZES.len() && value <= 0xFFFF => {
+                        let value = value as u16;
+                        let mut registers = cpu.registers();
+                        set_register(&mut registers, reg, value);
+                        cpu.set_registers(&registers);
+                        String::from("OK")
+                    }
+                    _ => String::from("E01"),
+                }
+            }
+            b"m" => {
+                // The reply may be shorter than asked, each byte taking two characters
+                let range = parse_range(args).and_then(|(addr, len)| {
+                    let len = cmp::min(len, (PACKET_SIZE - 4) / 2);
+                    addr.checked_add(len).map(|end| (addr, end))
+                });
+                match range {
+                    Some((addr, end)) => {
+                        let bytes: Result<Vec<u8>, _> = (addr..end)
+                            .map(|addr| cpu.memory().read_word(addr))
+                            .collect();
+                        match bytes {
+                            Ok(bytes) => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+                            Err(_) => String::from("E01"),
+                        }
+                    }
+                    None => String::from("E01"),
+                }
+            }
+            b"M" => {
+                let mut parts = args.splitn(2, |b| *b == b':');
+                let range = parts.next().and_then(parse_range);
+                let data = parts.next().and_then(decode_hex);
+                match (range, data) {
+                    (Some((addr, len)), Some(ref data)) if data.len() == len &&
+                                                           addr.checked_add(len).is_some() => {
+                        let result = data.iter()
+                            .enumerate()
+                            .map(|(idx, b)| cpu.memory_mut().write_word(addr + idx, *b))
+                            .collect::<Result<Vec<()>, _>>();
+                        match result {
+                            Ok(_) => String::from("OK"),
+                            Err(_) => String::from("E01"),
+                        }
+                    }
+                    _ => String::from("E01"),
+                }
+            }
+            b"Z" | b"z" => {
+                let mut parts = args.split(|b| *b == b',');
+                let kind = parts.next();
+                let addr = parts.next().and_then(parse_hex).filter(|addr| *addr <= 0xFFFF);
+                match (kind, addr) {
+                    (Some(b"0"), Some(addr)) => {
+                        let addr = addr as u16;
+                        if command == b"Z" {
+                            self.breakpoints.insert(addr);
+                        } else {
+                            self.breakpoints.remove(&addr);
+                        }
+                        String::from("OK")
+                    }
+                    // Only software breakpoints are supported
+                    (Some(_), Some(_)) => String::new(),
+                    _ => String::from("E01"),
+                }
+            }
+            b"s" | b"c" => {
+                if let Some(addr) = parse_hex(args).filter(|addr| *addr <= 0xFFFF) {
+                    let mut registers = cpu.registers();
+                    registers.pc = addr as u16;
+                    cpu.set_registers(&registers);
+                }
+                self.state = State::Running {
+                    steps: if command == b"s" { Some(1) } else { None },
+                    first_step: true,
+                    reverse: false,
+                };
+                // The stop reply is sent once the execution stops
+                return;
+            }
+            // Reverse step (bs) and continue (bc)
+            b"b" if args == b"s" || args == b"c" => {
+                self.state = State::Running {
+                    steps: if args == b"s" { Some(1) } else { None },
+                    first_step: true,
+                    reverse: true,
+                };
+                return;
+            }
+            b"k" => {
+                self.quit = true;
+                return;
+            }
+            b"D" => {
+                self.send_packet("OK");
+                self.detach();
+                return;
+            }
+            _ => String::new(),
+        };
+        self.send_packet(&reply);
+    }
+
+    fn query(&self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            format!("PacketSize={:x};qXfer:features:read+;ReverseStep+;ReverseContinue+",
+                    PACKET_SIZE)
+        } else if let Some(range) = query.strip_prefix(TARGET_XML_QUERY) {
+            match parse_range(range.as_bytes()) {
+                Some((offset, len)) => {
+                    let xml = TARGET_XML.as_bytes();
+                    let start = cmp::min(offset, xml.len());
+                    let end = cmp::min(start.saturating_add(len), xml.len());
+                    let chunk = String::from_utf8_lossy(&xml[start..end]);
+                    format!("{}{}", if end == xml.len() { "l" } else { "m" }, chunk)
+                }
+                None => String::from("E01"),
+            }
+        } else if query == "Attached" {
+            String::from("1")
+        } else {
+            String::new()
+        }
+    }
+
+    fn stop(&mut self, signal: u8) {
+        self.state = State::Paused;
+        self.send_packet(&format!("S{:02X}", signal));
+    }
+
+    // Let the CPU run freely.
+    fn detach(&mut self) {
+        self.stream = None;
+        self.received.clear();
+        self.state = State::Paused;
+    }
+
+    fn send_packet(&mut self, data: &str) {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.send_raw(packet.as_bytes());
+    }
+
+    fn send_raw(&mut self, data: &[u8]) {
+        let result = match self.stream {
+            Some(ref mut stream) => write_all(stream, data),
+            None => return,
+        };
+        if let Err(e) = result {
+            warn!("Can't write to GDB: {}.", e);
+            self.detach();
+        }
+    }
+}
+
+impl Controller for GdbStub {
+    // Execution errors stop the execution and are reported to the client as signals. Without a
+    // client anymore, they end the run.
+    fn step(&mut self, cpu: &mut CPU, com: &mut Communicator) -> Result<bool, Fault> {
+        if !self.poll(cpu) {
+            return Ok(false);
+        }
+        if self.state.is_reverse() && self.stream.is_some() {
+            if !self.history.step_back(cpu, com) {
+                // Tell the client that the beginning of the history was reached
+                self.state = State::Paused;
+                self.send_packet(&format!("T{:02X}replaylog:begin;", SIGTRAP));
+                return Ok(false);
+            }
+            return Ok(true);
+        }
+        if let Err(e) = self.history.step(cpu, com) {
+            if self.stream.is_none() {
+                self.quit = true;
+                return Err(cpu.fault(e));
+            }
+            let signal = match e {
+                CPUError::ParsingError(_) |
+                CPUError::InvalidRegister(_) => SIGILL,
+                _ => SIGSEGV,
+            };
+            self.stop(signal);
+            return Err(cpu.fault(e));
+        } else if !cpu.is_running() {
+            self.send_packet("W00");
+        }
+        Ok(true)
+    }
+
+    fn quit_requested(&self) -> bool {
+        self.quit
+    }
+}
+
+// The socket is non-blocking, so the writes may have to be retried.
+fn write_all(stream: &mut TcpStream, mut data: &[u8]) -> io::Result<()> {
+    while !data.is_empty() {
+        match stream.write(data) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "connection closed")),
+            Ok(len) => data = &data[len..],
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock ||
+                          e.kind() == io::ErrorKind::Interrupted => {
+                thread::sleep(Duration::from_millis(1))
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn register_values(registers: &Registers) -> [u16; 21] {
+    let mut values = [0u16; 21];
+    for (value, v) in values.iter_mut().zip(registers.v.iter()) {
+        *value = *v as u16;
+    }
+    values[16] = registers.i;
+    values[17] = registers.pc;
+    values[18] = registers.sp as u16;
+    values[19] = registers.dt as u16;
+    values[20] = registers.st as u16;
+    values
+}
+
+fn set_register(registers: &mut Registers, reg: usize, value: u16) {
+    match reg {
+        0..=15 => registers.v[reg] = value as u8,
+        16 => registers.i = value,
+        17 => registers.pc = value,
+        18 => registers.sp = value as u8,
+        19 => registers.dt = value as u8,
+        20 => registers.st = value as u8,
+        _ => (),
+    }
+}
+
+fn encode_register(reg: usize, value: u16) -> String {
+    match REGISTER_SIZES[reg] {
+        1 => format!("{:02x}", value as u8),
+        _ => format!("{:04x}", value),
+    }
+}
+
+fn parse_hex(s: &[u8]) -> Option<usize> {
+    str::from_utf8(s).ok().and_then(|s| usize::from_str_radix(s, 16).ok())
+}
+
+// Parse an "ADDR,LENGTH" pair.
+fn parse_range(s: &[u8]) -> Option<(usize, usize)> {
+    let mut parts = s.splitn(2, |b| *b == b',');
+    let addr = parts.next().and_then(parse_hex);
+    let len = parts.next().and_then(parse_hex);
+    match (addr, len) {
+        (Some(addr), Some(len)) => Some((addr, len)),
+        _ => None,
+    }
+}
+
+fn decode_hex(s: &[u8]) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    s.chunks(2).map(|byte| parse_hex(byte).map(|byte| byte as u8)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::prelude::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::{Duration, Instant};
+
+    use com::Communicator;
+    use cpu::{CPU, Fault};
+    use memory;
+    use debugger::Controller;
+    use super::{GdbStub, TARGET_XML, checksum_of};
+
+    // A client on one end of the socket, and the stub with its machine on the other.
+    struct Session {
+        client: TcpStream,
+        received: Vec<u8>,
+        stub: GdbStub,
+        cpu: CPU,
+        com: Communicator,
+        fault: Option<Fault>,
+    }
+
+    impl Session {
+        fn start(rom: Vec<u8>) -> Session {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            client.set_nonblocking(true).unwrap();
+            let stub = GdbStub::accept(&listener).unwrap();
+            let mut cpu = CPU::with_ram_size(memory::XO_RAM_SIZE);
+            cpu.load_rom(rom);
+            Session {
+                client,
+                received: Vec::new(),
+                stub,
+                cpu,
+                com: Communicator::new(),
+                fault: None,
+            }
+        }
+
+        // Send a packet, and step the stub until it replies.
+        fn exchange(&mut self, data: &[u8]) -> String {
+            let mut packet = vec![b'$'];
+            packet.extend_from_slice(data);
+            packet.extend_from_slice(format!("#{:02x}", checksum_of(data)).as_bytes());
+            self.client.write_all(&packet).unwrap();
+
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                assert!(Instant::now() < deadline,
+                        "no reply to {}",
+                        String::from_utf8_lossy(data));
+                if let Err(fault) = self.stub.step(&mut self.cpu, &mut self.com) {
+                    self.fault = Some(fault);
+                }
+                let mut buffer = [0u8; 4096];
+                match self.client.read(&mut buffer) {
+                    Ok(len) => self.received.extend_from_slice(&buffer[..len]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                    Err(e) => panic!("{}", e),
+                }
+                if let Some(reply) = self.reply() {
+                    return reply;
+                }
+            }
+        }
+
+        fn reply(&mut self) -> Option<String> {
+            let start = self.received.iter().position(|b| *b == b'$')?;
+            let end = start + self.received[start..].iter().position(|b| *b == b'#')?;
+            if end + 2 >= self.received.len() {
+                return None;
+            }
+            let packet: Vec<u8> = self.received.drain(..end + 3).collect();
+            let data = &packet[start + 1..end];
+            let checksum = String::from_utf8_lossy(&packet[packet.len() - 2..]).into_owned();
+            assert_eq!(u8::from_str_radix(&checksum, 16).unwrap(), checksum_of(data));
+            Some(String::from_utf8_lossy(data).into_owned())
+        }
+    }
+
+    #[test]
+    fn queries() {
+        let mut session = Session::start(vec![0x12, 0x00]);
+        let supported = session.exchange(b"qSupported:multiprocess+");
+        assert!(supported.contains("PacketSize=4000"));
+        assert!(supported.contains("qXfer:features:read+"));
+
+        let xml = session.exchange(b"qXfer:features:read:target.xml:0,fff");
+        assert_eq!(xml, format!("l{}", TARGET_XML));
+        let xml = session.exchange(b"qXfer:features:read:target.xml:0,10");
+        assert_eq!(xml, format!("m{}", &TARGET_XML[..0x10]));
+    }
+
+    #[test]
+    fn registers() {
+        let mut session = Session::start(vec![0x12, 0x00]);
+        let registers = session.exchange(b"g");
+        assert_eq!(registers.len(), 16 * 2 + 4 + 4 + 3 * 2);
+        assert_eq!(&registers[36..40], "0200");
+
+        assert_eq!(session.exchange(b"P11=0246"), "OK");
+        assert_eq!(session.exchange(b"p11"), "0246");
+        assert_eq!(session.exchange(b"P5=7f"), "OK");
+        assert_eq!(session.cpu.registers().v[5], 0x7F);
+        assert_eq!(session.cpu.registers().pc, 0x246);
+        assert_eq!(session.exchange(b"p15"), "E01");
+
+        // Garbage doesn't panic, it is read as zeros
+        assert_eq!(session.exchange(b"G\xff\xfe\xc3\xa9"), "OK");
+        assert_eq!(session.cpu.registers().pc, 0);
+    }
+
+    #[test]
+    fn memory() {
+        let mut session = Session::start(vec![0x12, 0x00]);
+        assert_eq!(session.exchange(b"M300,3:abcdef"), "OK");
+        assert_eq!(session.exchange(b"m300,3"), "abcdef");
+        assert_eq!(session.exchange(b"m200,2"), "1200");
+        assert_eq!(session.exchange(b"M300,2:\xff\xff\xff\xff"), "E01");
+        assert_eq!(session.exchange(b"mffffffffffffffff,2"), "E01");
+        // Reads are cut to what fits in a packet
+        assert_eq!(session.exchange(b"m0,ffff").len(), 0x4000 - 4);
+    }
+
+    #[test]
+    fn breakpoints_and_steps() {
+        // LD V0, 1; ADD V0, 1; JP 0x204
+        let mut session = Session::start(vec![0x60, 0x01, 0x70, 0x01, 0x12, 0x04]);
+        assert_eq!(session.exchange(b"Z0,202,2"), "OK");
+        assert_eq!(session.exchange(b"c"), "S05");
+        assert_eq!(session.cpu.registers().pc, 0x202);
+        assert_eq!(session.cpu.registers().v[0], 1);
+
+        assert_eq!(session.exchange(b"s"), "S05");
+        assert_eq!(session.cpu.registers().pc, 0x204);
+        assert_eq!(session.cpu.registers().v[0], 2);
+
+        assert_eq!(session.exchange(b"z0,202,2"), "OK");
+        assert_eq!(session.exchange(b"Z1,202,2"), "");
+    }
+
+    #[test]
+    fn faults() {
+        // RET with an empty stack
+        let mut session = Session::start(vec![0x00, 0xEE]);
+        assert_eq!(session.exchange(b"c"), "S0B");
+        assert_eq!(session.fault.take().map(|fault| fault.pc), Some(0x200));
+        assert!(!session.stub.quit_requested());
+
+        // Without a client anymore, the fault ends the run
+        assert_eq!(session.exchange(b"D"), "OK");
+        assert_eq!(session.fault.take().map(|fault| fault.pc), Some(0x200));
+        assert!(session.stub.quit_requested());
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::VecDeque;
+
+use com::Communicator;
+use cpu::{CPU, CPUError, Undo};
+
+// Memory used by the steps kept to be undone, in bytes, the oldest ones being forgotten past
+// that. The steps rewriting the whole display are much bigger than the others.
+const MEMORY_LIMIT: usize = 32 * 1024 * 1024;
+
+// Execution history, to step backwards.
+pub struct History {
+    steps: VecDeque<Undo>,
+    used: usize,
+}
+
+impl History {
+    pub fn new() -> History {
+        History {
+            steps: VecDeque::new(),
+            used: 0,
+        }
+    }
+
+    // Execute a step forward. A step that failed is undone, so that the CPU is left right
+    // before the faulting instruction.
+    pub fn step(&mut self, cpu: &mut CPU, com: &mut Communicator) -> Result<(), CPUError> {
+        let (undo, result) = cpu.step_with_undo(com);
+        match result {
+            Ok(()) => {
+                self.used += undo.size();
+                self.steps.push_back(undo);
+                while self.used > MEMORY_LIMIT {
+                    match self.steps.pop_front() {
+                        Some(oldest) => self.used -= oldest.size(),
+                        None => break,
+                    }
+                }
+                Ok(())
+            }
+            Err(e) => {
+                cpu.undo(com, undo);
+                Err(e)
+            }
+        }
+    }
+
+    // Undo the last step, returns false if there is none left.
+    pub fn step_back(&mut self, cpu: &mut CPU, com: &mut Communicator) -> bool {
+        match self.steps.pop_back() {
+            Some(undo) => {
+                self.used -= undo.size();
+                cpu.undo(com, undo);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{History, MEMORY_LIMIT};
+    use com::Communicator;
+    use cpu::CPU;
+
+    #[test]
+    fn memory_limit() {
+        let mut cpu = CPU::new();
+        // HIGH; CLS; JP 0x202
+        cpu.load_rom(vec![0x00, 0xFF, 0x00, 0xE0, 0x12, 0x02]);
+        let mut com = Communicator::new();
+        let mut history = History::new();
+        for _ in 0..20000 {
+            history.step(&mut cpu, &mut com).unwrap();
+        }
+        assert!(history.used <= MEMORY_LIMIT);
+        assert!(history.steps.len() < 20000);
+
+        let oldest = cpu.cycles() - history.steps.len() as u64;
+        while history.step_back(&mut cpu, &mut com) {}
+        assert_eq!(history.used, 0);
+        assert_eq!(cpu.cycles(), oldest);
+    }
+}
@@ -0,0 +1,398 @@
+mod command;
+mod gdb;
+mod history;
+
+use std::io;
+use std::io::prelude::*;
+use std::thread;
+use std::time::Duration;
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+
+use com::Communicator;
+use cpu::{CPU, Fault};
+use cpu::inst::Instruction;
+use memory::Memory;
+use Options;
+use self::command::{Breakpoint, Command};
+use self::history::History;
+
+pub use self::gdb::GdbStub;
+
+const PROMPT: &str = "(tw-chip8) ";
+
+// Drives the CPU in place of the main loop, so that the execution can be paused and inspected.
+pub trait Controller {
+    // Step the CPU if allowed to, returns false if the CPU didn't move (paused). Execution errors
+    // stop it, the controller pausing on them (or quitting if nobody is there to inspect them).
+    fn step(&mut self, cpu: &mut CPU, com: &mut Communicator) -> Result<bool, Fault>;
+    fn quit_requested(&self) -> bool;
+}
+
+// Create the controller requested by the options, if any. Waits for the GDB connection when a
+// GDB port is set.
+pub fn from_options(options: &Options) -> io::Result<Option<Box<dyn Controller>>> {
+    if let Some(port) = options.gdb_port {
+        Ok(Some(Box::new(GdbStub::listen(port)?)))
+    } else if options.debug {
+        Ok(Some(Box::new(Debugger::new())))
+    } else {
+        Ok(None)
+    }
+}
+
+enum Input {
+    Line(String),
+    Nothing,
+    Closed,
+}
+
+// Reads the standard input on its own thread, so that the emulator (and its window) keeps
+// running while waiting for commands.
+struct Console {
+    lines: Receiver<String>,
+    pending: VecDeque<String>,
+    closed: bool,
+}
+
+impl Console {
+    fn new() -> Console {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            for line in stdin.lock().lines() {
+                match line {
+                    Ok(line) => {
+                        if sender.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+        Console {
+            lines: receiver,
+            pending: VecDeque::new(),
+            closed: false,
+        }
+    }
+
+    fn receive(&mut self) {
+        loop {
+            match self.lines.try_recv() {
+                Ok(line) => self.pending.push_back(line),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.closed = true;
+                    break;
+                }
+            }
+        }
+    }
+
+    fn read(&mut self) -> Input {
+        self.receive();
+        match self.pending.pop_front() {
+            Some(line) => Input::Line(line),
+            None if self.closed => Input::Closed,
+            None => Input::Nothing,
+        }
+    }
+
+    // Lines typed while running are kept for when the execution pauses, except for "pause"
+    // which interrupts it.
+    fn interrupted(&mut self) -> bool {
+        self.receive();
+        match self.pending.iter().position(|line| line.trim() == "pause") {
+            Some(idx) => {
+                self.pending.remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+enum State {
+    Paused,
+    // The breakpoints are ignored for the first step, so that we can resume from one. Reverse
+    // execution undoes the steps instead.
+    Running {
+        steps: Option<u64>,
+        first_step: bool,
+        reverse: bool,
+    },
+}
+
+impl State {
+    fn is_reverse(&self) -> bool {
+        match *self {
+            State::Running { reverse, .. } => reverse,
+            State::Paused => false,
+        }
+    }
+}
+
+pub struct Debugger {
+    console: Console,
+    breakpoints: Vec<Breakpoint>,
+    history: History,
+    state: State,
+    prompted: bool,
+    last_command: Option<Command>,
+    quit: bool,
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            console: Console::new(),
+            breakpoints: Vec::new(),
+            history: History::new(),
+            state: State::Paused,
+            prompted: false,
+            last_command: None,
+            quit: false,
+        }
+    }
+
+    // Handle the commands and breakpoints, returns true if the CPU may execute its next step.
+    fn poll(&mut self, cpu: &CPU) -> bool {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+
+        if let State::Running { steps, first_step, reverse } = self.state {
+            if self.console.interrupted() || steps == Some(0) {
+                self.pause(cpu, &mut out);
+            } else if !first_step && self.breakpoint_hit(cpu) {
+                let _ = writeln!(out, "Breakpoint hit.");
+                self.pause(cpu, &mut out);
+            } else {
+                self.state = State::Running {
+                    steps: steps.map(|steps| steps - 1),
+                    first_step: false,
+                    reverse,
+                };
+                return true;
+            }
+        }
+
+        if let State::Paused = self.state {
+            if !self.prompted && !self.quit {
+                let _ = write!(out, "{}", PROMPT);
+                let _ = out.flush();
+                self.prompted = true;
+            }
+
+            match self.console.read() {
+                Input::Line(line) => {
+                    self.prompted = false;
+                    self.execute(&line, cpu, &mut out);
+                }
+                Input::Nothing => thread::sleep(Duration::from_millis(10)),
+                Input::Closed => self.quit = true,
+            }
+        }
+        false
+    }
+
+    fn pause<W>(&mut self, cpu: &CPU, out: &mut W)
+        where W: Write
+    {
+        if let State::Running { .. } = self.state {
+            let (line, _) = disassemble_at(cpu.memory(), cpu.registers().pc as usize);
+            let _ = writeln!(out, "{}", line);
+        }
+        self.state = State::Paused;
+        self.prompted = false;
+    }
+
+    fn add_breakpoint<W>(&mut self, breakpoint: Breakpoint, out: &mut W) -> io::Result<()>
+        where W: Write
+    {
+        writeln!(out, "Breakpoint {} {}.", self.breakpoints.len(), breakpoint)?;
+        self.breakpoints.push(breakpoint);
+        Ok(())
+    }
+
+    fn breakpoint_hit(&self, cpu: &CPU) -> bool {
+        let pc = cpu.registers().pc;
+        let opcode = cpu.memory().read_dword(pc as usize).unwrap_or(0);
+        self.breakpoints.iter().any(|breakpoint| breakpoint.hit(pc, opcode))
+    }
+
+    fn execute<W>(&mut self, line: &str, cpu: &CPU, out: &mut W)
+        where W: Write
+    {
+        let command = if line.trim().is_empty() {
+            match self.last_command {
+                Some(command) => command,
+                None => return,
+            }
+        } else {
+            match Command::parse(line) {
+                Ok(command) => command,
+                Err(e) => {
+                    let _ = writeln!(out, "Error: {}.", e);
+                    return;
+                }
+            }
+        };
+        self.last_command = Some(command);
+
+        if let Err(e) = self.run_command(command, cpu, out) {
+            warn!("Can't write debugger output: {}.", e);
+        }
+    }
+
+    fn run_command<W>(&mut self, command: Command, cpu: &CPU, out: &mut W) -> io::Result<()>
+        where W: Write
+    {
+        match command {
+            Command::Step(steps) => {
+                self.state = State::Running {
+                    steps: Some(steps),
+                    first_step: true,
+                    reverse: false,
+                }
+            }
+            Command::Continue => {
+                self.state = State::Running {
+                    steps: None,
+                    first_step: true,
+                    reverse: false,
+                }
+            }
+            Command::ReverseStep(steps) => {
+                self.state = State::Running {
+                    steps: Some(steps),
+                    first_step: true,
+                    reverse: true,
+                }
+            }
+            Command::ReverseContinue => {
+                self.state = State::Running {
+                    steps: None,
+                    first_step: true,
+                    reverse: true,
+                }
+            }
+            Command::Pause => (),
+            Command::Break(addr) => self.add_breakpoint(Breakpoint::Address(addr), out)?,
+            Command::BreakOpcode(value, mask) => {
+                self.add_breakpoint(Breakpoint::Opcode { value, mask }, out)?
+            }
+            Command::Delete(Some(idx)) => {
+                if idx < self.breakpoints.len() {
+                    self.breakpoints.remove(idx);
+                } else {
+                    writeln!(out, "No breakpoint {}.", idx)?;
+                }
+            }
+            Command::Delete(None) => self.breakpoints.clear(),
+            Command::Breakpoints => {
+                for (idx, breakpoint) in self.breakpoints.iter().enumerate() {
+                    writeln!(out, "{}: {}", idx, breakpoint)?;
+                }
+            }
+            Command::Registers => write!(out, "{}", cpu.registers())?,
+            Command::Memory(addr, len) => hexdump(cpu.memory(), addr as usize, len as usize, out)?,
+            Command::Disassemble(addr, count) => {
+                let pc = cpu.registers().pc as usize;
+                let mut addr = match addr {
+                    Some(addr) => addr as usize,
+                    None => pc.saturating_sub(8),
+                };
+                for _ in 0..count {
+                    let (line, size) = disassemble_at(cpu.memory(), addr);
+                    writeln!(out, "{} {}", if addr == pc { "=>" } else { "  " }, line)?;
+                    addr += size;
+                }
+            }
+            Command::Help => writeln!(out, "{}", command::HELP)?,
+            Command::Quit => self.quit = true,
+        }
+        Ok(())
+    }
+}
+
+impl Controller for Debugger {
+    // Execution errors pause the execution right before the faulting instruction, so that the
+    // machine state can be inspected (and stepped back from).
+    fn step(&mut self, cpu: &mut CPU, com: &mut Communicator) -> Result<bool, Fault> {
+        if !self.poll(cpu) {
+            return Ok(false);
+        }
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        if self.state.is_reverse() {
+            if !self.history.step_back(cpu, com) {
+                let _ = writeln!(out, "No more history to go back to.");
+                self.pause(cpu, &mut out);
+                return Ok(false);
+            }
+        } else if let Err(e) = self.history.step(cpu, com) {
+            self.pause(cpu, &mut out);
+            return Err(cpu.fault(e));
+        }
+        Ok(true)
+    }
+
+    fn quit_requested(&self) -> bool {
+        self.quit
+    }
+}
+
+// Disassemble the instruction at the given address, returns the line and the instruction size.
+fn disassemble_at(memory: &Memory, addr: usize) -> (String, usize) {
+    let dword = match memory.read_dword(addr) {
+        Ok(dword) => dword,
+        Err(_) => return (format!("{:#06X}: out of memory", addr), 2),
+    };
+
+    if Instruction::is_long(dword) {
+        let operand = memory.read_dword(addr + 2).unwrap_or(0);
+        if let Ok(inst) = Instruction::from_binary_long(dword, operand) {
+            return (format!("{:#06X}: {:04X} {:04X}  {}", addr, dword, operand, inst), 4);
+        }
+    }
+    match Instruction::from_binary(dword) {
+        Ok(inst) => (format!("{:#06X}: {:04X}       {}", addr, dword, inst), 2),
+        Err(_) => (format!("{:#06X}: {:04X}       ???", addr, dword), 2),
+    }
+}
+
+fn hexdump<W>(memory: &Memory, addr: usize, len: usize, out: &mut W) -> io::Result<()>
+    where W: Write
+{
+    for line in 0..len.div_ceil(16) {
+        let start = addr + line * 16;
+        let bytes: Vec<u8> = (start..start + 16)
+            .take(addr + len - start)
+            .filter_map(|addr| memory.read_word(addr).ok())
+            .collect();
+        if bytes.is_empty() {
+            break;
+        }
+
+        write!(out, "{:04X}: ", start)?;
+        for byte in bytes.iter() {
+            write!(out, "{:02X} ", byte)?;
+        }
+        for _ in bytes.len()..16 {
+            write!(out, "   ")?;
+        }
+        let ascii: String = bytes.iter()
+            .map(|b| if *b >= 0x20 && *b < 0x7F { *b as char } else { '.' })
+            .collect();
+        writeln!(out, "|{}|", ascii)?;
+    }
+    Ok(())
+}
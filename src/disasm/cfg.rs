@@ -0,0 +1,222 @@
+// Control-flow graph of the reachable code, as basic blocks linked by the jumps, calls, returns
+// and skips between them, written in the Graphviz DOT format. Everything is kept sorted by
+// address, so that the same ROM always gives the same output.
+
+use std::fmt;
+use std::collections::{BTreeMap, BTreeSet};
+
+use cpu::inst::Instruction;
+use super::{ORIGIN, Program, label};
+
+#[derive(Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+enum Edge {
+    Next,
+    Jump,
+    Call,
+    Return,
+    // Both ways of a skip instruction (SE, SNE, SKP, SKNP)
+    NoSkip,
+    Skip,
+}
+
+impl Edge {
+    fn attributes(&self) -> &'static str {
+        match *self {
+            Edge::Next => "",
+            Edge::Jump => " [label=\"jump\"]",
+            Edge::Call => " [label=\"call\", style=bold]",
+            Edge::Return => " [label=\"return\", style=dashed]",
+            Edge::NoSkip => " [label=\"no skip\"]",
+            Edge::Skip => " [label=\"skip\"]",
+        }
+    }
+
+    // Whether the edge stays in the current subroutine.
+    fn is_local(&self) -> bool {
+        *self != Edge::Call && *self != Edge::Return
+    }
+}
+
+struct Block {
+    // Address of each instruction
+    instructions: Vec<usize>,
+    edges: BTreeSet<(usize, Edge)>,
+}
+
+pub struct Graph<'a> {
+    program: &'a Program,
+    blocks: BTreeMap<usize, Block>,
+}
+
+impl<'a> Graph<'a> {
+    pub fn build(program: &'a Program) -> Graph<'a> {
+        // The blocks start at the entry point, at the jump and call targets, and after any
+        // instruction that doesn't simply go on with the next one
+        let mut leaders: BTreeSet<usize> = program.labels.clone();
+        leaders.insert(ORIGIN);
+        for (addr, &(ref inst, size)) in program.code.iter() {
+            let flow = flow(program, *addr, inst, size);
+            if flow.len() != 1 || flow[0].1 != Edge::Next {
+                leaders.extend(flow.iter().map(|&(target, _)| target));
+                leaders.insert(addr + size);
+            }
+        }
+
+        let mut blocks: BTreeMap<usize, Block> = BTreeMap::new();
+        let mut current = None;
+        for (addr, &(ref inst, size)) in program.code.iter() {
+            let extend = match current {
+                Some(start) => {
+                    !leaders.contains(addr) && block_end(program, &blocks[&start]) == *addr
+                }
+                None => false,
+            };
+            if !extend {
+                blocks.insert(*addr,
+                              Block {
+                                  instructions: Vec::new(),
+                                  edges: BTreeSet::new(),
+                              });
+                current = Some(*addr);
+            }
+
+            // The edges of the block are the ones of its last instruction
+            let block = blocks.get_mut(&current.unwrap()).unwrap();
+            block.instructions.push(*addr);
+            block.edges = flow(program, *addr, inst, size)
+                .into_iter()
+                .filter(|&(target, _)| program.code.contains_key(&target))
+                .collect();
+        }
+
+        let mut graph = Graph { program, blocks };
+        graph.link_returns();
+        graph
+    }
+
+    // Link the returns of each subroutine to the instruction following its calls.
+    fn link_returns(&mut self) {
+        let mut returns = Vec::new();
+        for (addr, &(ref inst, size)) in self.program.code.iter() {
+            let target = match *inst {
+                Instruction::CALL(target) => target as usize,
+                _ => continue,
+            };
+            let return_site = addr + size;
+            if !self.blocks.contains_key(&target) || !self.blocks.contains_key(&return_site) {
+                continue;
+            }
+
+            // Walk the subroutine, without going into the ones it calls
+            let mut visited = BTreeSet::new();
+            let mut pending = vec![target];
+            while let Some(start) = pending.pop() {
+                if !visited.insert(start) {
+                    continue;
+                }
+                let block = &self.blocks[&start];
+                let last = *block.instructions.last().unwrap();
+                if let Instruction::RET = self.program.code[&last].0 {
+                    returns.push((start, return_site));
+                }
+                pending.extend(block.edges
+                                   .iter()
+                                   .filter(|&&(_, edge)| edge.is_local())
+                                   .map(|&(target, _)| target));
+            }
+        }
+
+        for (start, return_site) in returns {
+            if let Some(block) = self.blocks.get_mut(&start) {
+                block.edges.insert((return_site, Edge::Return));
+            }
+        }
+    }
+}
+
+// Address following the last instruction of the block.
+fn block_end(program: &Program, block: &Block) -> usize {
+    let last = *block.instructions.last().unwrap();
+    last + program.code[&last].1
+}
+
+// Where the execution may go after the instruction.
+fn flow(program: &Program, addr: usize, inst: &Instruction, size: usize) -> Vec<(usize, Edge)> {
+    use cpu::inst::Instruction::*;
+
+    let next = addr + size;
+    match *inst {
+        JP(target) => vec![(target as usize, Edge::Jump)],
+        CALL(target) => vec![(target as usize, Edge::Call), (next, Edge::Next)],
+        RET | EXIT | JPO(_) => Vec::new(),
+        SE(..) | SNE(..) | SKP(_) | SKNP(_) => {
+            let skipped = program.code.get(&next).map_or(2, |&(_, size)| size);
+            vec![(next, Edge::NoSkip), (next + skipped, Edge::Skip)]
+        }
+        _ => vec![(next, Edge::Next)],
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl<'a> fmt::Display for Graph<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "digraph cfg {{")?;
+        writeln!(f, "    node [shape=box, fontname=\"monospace\"];")?;
+        for (start, block) in self.blocks.iter() {
+            let mut text = format!("{}:\\l", label(*start));
+            for addr in block.instructions.iter() {
+                let inst = self.program.format_instruction(*addr, &self.program.code[addr].0);
+                text.push_str(&escape(&format!("{:#X}  {}", addr, inst)));
+                text.push_str("\\l");
+            }
+            let color = if self.program.unresolved.contains(block.instructions.last().unwrap()) {
+                ", color=red"
+            } else {
+                ""
+            };
+            writeln!(f, "    {} [label=\"{}\"{}];", label(*start), text, color)?;
+        }
+        for (start, block) in self.blocks.iter() {
+            for &(target, edge) in block.edges.iter() {
+                writeln!(f,
+                         "    {} -> {}{};",
+                         label(*start),
+                         label(target),
+                         edge.attributes())?;
+            }
+        }
+        writeln!(f, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Graph;
+    use disasm::Program;
+
+    #[test]
+    fn dot_output() {
+        // CALL 0x208; SE V0, 0; JP V0, 0x20C; JP 0x206; LD V0, 1; RET
+        let rom = [0x22, 0x08, 0x30, 0x00, 0xB2, 0x0C, 0x12, 0x06, 0x60, 0x01, 0x00, 0xEE];
+        let program = Program::analyze(&rom);
+        let expected = r#"digraph cfg {
+    node [shape=box, fontname="monospace"];
+    L_200 [label="L_200:\l0x200  CALL L_208\l"];
+    L_202 [label="L_202:\l0x202  SE V0, 0\l"];
+    L_204 [label="L_204:\l0x204  JP V0, 0x20C ; unresolved target\l", color=red];
+    L_206 [label="L_206:\l0x206  JP L_206\l"];
+    L_208 [label="L_208:\l0x208  LD V0, 1\l0x20A  RET\l"];
+    L_200 -> L_202;
+    L_200 -> L_208 [label="call", style=bold];
+    L_202 -> L_204 [label="no skip"];
+    L_202 -> L_206 [label="skip"];
+    L_206 -> L_206 [label="jump"];
+    L_208 -> L_202 [label="return", style=dashed];
+}
+"#;
+        assert_eq!(Graph::build(&program).to_string(), expected);
+    }
+}
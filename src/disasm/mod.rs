@@ -0,0 +1,226 @@
+// Disassembler following the control flow of the program from its entry point, so that only the
+// reachable instructions are decoded as such, wherever they are aligned. Everything else is
+// printed as data, one byte per line with its bit pattern for what looks like sprites (the
+// addresses loaded in I).
+//
+// The listing can be fed back to the assembler to get the same ROM.
+
+mod cfg;
+
+use std::fmt;
+use std::collections::{BTreeMap, BTreeSet};
+
+use cpu::inst::Instruction;
+
+pub use self::cfg::Graph;
+
+// Address at which the ROM is loaded, and where the execution starts.
+const ORIGIN: usize = 0x200;
+
+// Data bytes printed per line, outside of sprites.
+const BYTES_PER_LINE: usize = 8;
+
+pub struct Program {
+    data: Vec<u8>,
+    // Reachable instructions and their size, by address
+    code: BTreeMap<usize, (Instruction, usize)>,
+    // Jump and call targets
+    labels: BTreeSet<usize>,
+    // JP V0 instructions, whose targets depend on the value of V0
+    unresolved: BTreeSet<usize>,
+    // Addresses loaded in I that aren't code
+    sprites: BTreeSet<usize>,
+}
+
+impl Program {
+    pub fn analyze(data: &[u8]) -> Program {
+        use cpu::inst::Instruction::*;
+
+        let mut program = Program {
+            data: data.to_vec(),
+            code: BTreeMap::new(),
+            labels: BTreeSet::new(),
+            unresolved: BTreeSet::new(),
+            sprites: BTreeSet::new(),
+        };
+
+        let mut pending = vec![ORIGIN];
+        while let Some(addr) = pending.pop() {
+            if program.code.contains_key(&addr) {
+                continue;
+            }
+            let (inst, size) = match program.decode(addr) {
+                Some(decoded) => decoded,
+                None => continue,
+            };
+
+            let next = addr + size;
+            match inst {
+                JP(target) => {
+                    program.labels.insert(target as usize);
+                    pending.push(target as usize);
+                }
+                CALL(target) => {
+                    program.labels.insert(target as usize);
+                    pending.push(target as usize);
+                    pending.push(next);
+                }
+                JPO(_) => {
+                    program.unresolved.insert(addr);
+                }
+                RET | EXIT => (),
+                SE(..) | SNE(..) | SKP(_) | SKNP(_) => {
+                    pending.push(next);
+                    pending.push(next + program.decode(next).map_or(2, |(_, size)| size));
+                }
+                LDI(target) | LDIL(target) => {
+                    program.sprites.insert(target as usize);
+                    pending.push(next);
+                }
+                _ => pending.push(next),
+            }
+            program.code.insert(addr, (inst, size));
+        }
+
+        // Only keep what ended up being in the ROM, as code for the labels and data for the
+        // sprites
+        let end = ORIGIN + program.data.len();
+        program.labels = program.labels
+            .iter()
+            .cloned()
+            .filter(|addr| program.code.contains_key(addr))
+            .collect();
+        program.sprites = program.sprites
+            .iter()
+            .cloned()
+            .filter(|addr| *addr >= ORIGIN && *addr < end && !program.code.contains_key(addr))
+            .collect();
+        program
+    }
+
+    // Decode the instruction at the given address, along with its size, if it is in the ROM.
+    fn decode(&self, addr: usize) -> Option<(Instruction, usize)> {
+        if addr < ORIGIN || addr + 2 > ORIGIN + self.data.len() {
+            return None;
+        }
+        let dword = self.dword(addr);
+        if Instruction::is_long(dword) {
+            if addr + 4 > ORIGIN + self.data.len() {
+                return None;
+            }
+            let operand = self.dword(addr + 2);
+            return Instruction::from_binary_long(dword, operand).ok().map(|inst| (inst, 4));
+        }
+        Instruction::from_binary(dword).ok().map(|inst| (inst, 2))
+    }
+
+    fn dword(&self, addr: usize) -> u16 {
+        let offset = addr - ORIGIN;
+        (self.data[offset] as u16) << 8 | self.data[offset + 1] as u16
+    }
+
+    fn byte(&self, addr: usize) -> u8 {
+        self.data[addr - ORIGIN]
+    }
+
+    fn format_instruction(&self, addr: usize, inst: &Instruction) -> String {
+        match *inst {
+            Instruction::JP(target) if self.labels.contains(&(target as usize)) => {
+                format!("JP {}", label(target as usize))
+            }
+            Instruction::CALL(target) if self.labels.contains(&(target as usize)) => {
+                format!("CALL {}", label(target as usize))
+            }
+            _ if self.unresolved.contains(&addr) => format!("{} ; unresolved target", inst),
+            _ => inst.to_string(),
+        }
+    }
+}
+
+fn label(addr: usize) -> String {
+    format!("L_{:03X}", addr)
+}
+
+fn sprite_row(byte: u8) -> String {
+    format!("{:08b}", byte).replace('0', ".").replace('1', "#")
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let end = ORIGIN + self.data.len();
+        let mut lines = Vec::new();
+        let mut placed = BTreeSet::new();
+
+        let mut addr = ORIGIN;
+        let mut in_sprite = false;
+        while addr < end {
+            if let Some(&(ref inst, size)) = self.code.get(&addr) {
+                in_sprite = false;
+                if self.labels.contains(&addr) {
+                    lines.push(format!("{}:", label(addr)));
+                    placed.insert(addr);
+                }
+                lines.push(format!("{:#X}\t| {}", addr, self.format_instruction(addr, inst)));
+                addr += size;
+                continue;
+            }
+
+            // Data until the next instruction, sprites getting a line per row
+            let data_end = self.code.range(addr..).next().map_or(end, |(next, _)| *next);
+            in_sprite = in_sprite || self.sprites.contains(&addr);
+            if in_sprite {
+                let byte = self.byte(addr);
+                lines.push(format!("{:#X}\t| DB {:#04X} ; {}", addr, byte, sprite_row(byte)));
+                addr += 1;
+            } else {
+                let run_end = self.sprites.range(addr..data_end).next().cloned().unwrap_or(data_end);
+                let count = (run_end - addr).min(BYTES_PER_LINE);
+                let bytes: Vec<String> = (addr..addr + count)
+                    .map(|addr| format!("{:#04X}", self.byte(addr)))
+                    .collect();
+                lines.push(format!("{:#X}\t| DB {}", addr, bytes.join(", ")));
+                addr += count;
+            }
+        }
+
+        // Targets in the middle of another instruction can't be put on a line of their own
+        for addr in self.labels.difference(&placed) {
+            writeln!(f, "{} EQU {:#X}", label(*addr), addr)?;
+        }
+        for line in lines {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+// Listing of the whole ROM decoded as instructions, two bytes at a time. Words that aren't
+// instructions are printed as data, so that the listing can be assembled back.
+pub struct Linear<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Linear<'a> {
+    pub fn new(data: &'a [u8]) -> Linear<'a> {
+        Linear { data }
+    }
+}
+
+impl<'a> fmt::Display for Linear<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut addr = ORIGIN;
+        for bytes in self.data.chunks(2) {
+            if bytes.len() < 2 {
+                writeln!(f, "{:#X}\t| DB {:#04X}", addr, bytes[0])?;
+                break;
+            }
+            let dword = (bytes[0] as u16) << 8 | bytes[1] as u16;
+            match Instruction::from_binary(dword) {
+                Ok(inst) => writeln!(f, "{:#X}\t| {}", addr, inst)?,
+                Err(_) => writeln!(f, "{:#X}\t| DW {:#06X}", addr, dword)?,
+            }
+            addr += 2;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,310 @@
+// Animated GIF recording of the display, one image per 60 Hz frame. The pixel values are the
+// indexes of a 4-color global palette, and a frame identical to the previous one only makes
+// that one last longer. GIF delays are in hundredths of a second, rounded so that the animation
+// doesn't drift from emulated time. Browsers slow down the images shorter than MIN_DELAY, such
+// an image is replaced by the next one instead, the animation then showing fewer frames.
+//
+// The size of the animation is the one of the display when the recording starts, frames at
+// another resolution being stretched to it.
+
+use std::io;
+use std::cmp;
+use std::collections::HashMap;
+
+use cpu::{CPU, TIMER_FREQUENCY};
+use com::video::VideoCommunicator;
+use screenshot::Settings;
+use binary::write_u16_le;
+
+// Codes are at most 12 bits long.
+const MAX_CODES: u16 = 4096;
+
+// Bits of a color index, the palette having 4 colors.
+const MIN_CODE_SIZE: u8 = 2;
+
+// Shortest delay of an image, in hundredths of a second.
+const MIN_DELAY: u64 = 2;
+
+// A frame waiting for the next different one, to know how long it lasts.
+struct Pending {
+    pixels: Vec<u8>,
+    start: u64,
+}
+
+pub struct GifRecorder<W>
+    where W: io::Write
+{
+    out: W,
+    settings: Settings,
+    // Size of the animation, scaled, once the first frame is there
+    size: Option<(usize, usize)>,
+    pending: Option<Pending>,
+    // Frames recorded so far, and the last frame of the CPU seen
+    time: u64,
+    last_frame: Option<u64>,
+}
+
+impl<W> GifRecorder<W>
+    where W: io::Write
+{
+    pub fn new(out: W, settings: &Settings) -> GifRecorder<W> {
+        GifRecorder {
+            out,
+            settings: *settings,
+            size: None,
+            pending: None,
+            time: 0,
+            last_frame: None,
+        }
+    }
+
+    // Call after each step, the display being captured whenever a new frame starts. Frames
+    // going back (rewinding, loading a state) are recorded as they come.
+    pub fn record(&mut self, cpu: &CPU, video: &VideoCommunicator) -> io::Result<()> {
+        let frame = cpu.frames();
+        match self.last_frame {
+            Some(last) if last == frame => return Ok(()),
+            // Several frames may go by in one step with the VIP timing
+            Some(last) if frame > last => self.time += frame - last,
+            Some(_) => self.time += 1,
+            None => (),
+        }
+        self.last_frame = Some(frame);
+        self.capture(&video.display, video.width)
+    }
+
+    // Add the display as the image of the next frame, for recordings that aren't driven by a
+    // CPU.
+    pub fn add_frame(&mut self, display: &[u8], width: usize) -> io::Result<()> {
+        if self.size.is_some() {
+            self.time += 1;
+        }
+        self.capture(display, width)
+    }
+
+    // Add the display as the image of the current time.
+    fn capture(&mut self, display: &[u8], width: usize) -> io::Result<()> {
+        let (image_width, image_height) = match self.size {
+            Some(size) => size,
+            None => {
+                let scale = self.settings.scale.max(1);
+                let size = (width * scale, display.len() / width * scale);
+                self.write_header(size)?;
+                self.size = Some(size);
+                size
+            }
+        };
+
+        // Nearest neighbor scaling, from the display to the animation size
+        let height = display.len() / width;
+        let mut pixels = Vec::with_capacity(image_width * image_height);
+        for y in 0..image_height {
+            let line = y * height / image_height * width;
+            for x in 0..image_width {
+                pixels.push(display[line + x * width / image_width] & 3);
+            }
+        }
+
+        let time = self.time;
+        match self.pending {
+            Some(ref pending) if pending.pixels == pixels => return Ok(()),
+            Some(ref mut pending) if centiseconds(time) - centiseconds(pending.start) <
+                                     MIN_DELAY => {
+                pending.pixels = pixels;
+                return Ok(());
+            }
+            _ => (),
+        }
+        self.flush_pending()?;
+        self.pending = Some(Pending {
+                                pixels,
+                                start: time,
+                            });
+        Ok(())
+    }
+
+    // End the animation, the last frame lasting one frame more. Returns the output.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.size.is_none() {
+            // Nothing was recorded, the animation is a single empty frame
+            self.capture(&[0], 1)?;
+        }
+        self.time += 1;
+        self.flush_pending()?;
+        self.out.write_all(&[0x3B])?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn write_header(&mut self, (width, height): (usize, usize)) -> io::Result<()> {
+        self.out.write_all(b"GIF89a")?;
+        write_u16_le(&mut self.out, width as u16)?;
+        write_u16_le(&mut self.out, height as u16)?;
+        // Global palette of 4 colors, background color and pixel aspect ratio
+        self.out.write_all(&[0xF1, 0, 0])?;
+        for color in self.settings.palette.iter() {
+            self.out.write_all(color)?;
+        }
+
+        // Loop forever
+        self.out.write_all(&[0x21, 0xFF, 11])?;
+        self.out.write_all(b"NETSCAPE2.0")?;
+        self.out.write_all(&[3, 1, 0, 0, 0])
+    }
+
+    fn flush_pending(&mut self) -> io::Result<()> {
+        let pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        let (width, height) = self.size.unwrap();
+
+        // Graphic control extension: the delay of the frame, only the last one may be too short
+        let delay = cmp::max(centiseconds(self.time) - centiseconds(pending.start), MIN_DELAY);
+        self.out.write_all(&[0x21, 0xF9, 4, 0])?;
+        write_u16_le(&mut self.out, delay as u16)?;
+        self.out.write_all(&[0, 0])?;
+
+        // Image descriptor, covering the whole animation
+        self.out.write_all(&[0x2C, 0, 0, 0, 0])?;
+        write_u16_le(&mut self.out, width as u16)?;
+        write_u16_le(&mut self.out, height as u16)?;
+        self.out.write_all(&[0])?;
+
+        self.out.write_all(&[MIN_CODE_SIZE])?;
+        let data = compress(&pending.pixels);
+        for block in data.chunks(255) {
+            self.out.write_all(&[block.len() as u8])?;
+            self.out.write_all(block)?;
+        }
+        self.out.write_all(&[0])
+    }
+}
+
+// Time of the start of a frame, in hundredths of a second.
+fn centiseconds(frame: u64) -> u64 {
+    (frame * 100 + TIMER_FREQUENCY as u64 / 2) / TIMER_FREQUENCY as u64
+}
+
+// Codes written least significant bit first.
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    bits: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u16, size: u8) {
+        self.buffer |= (code as u32) << self.bits;
+        self.bits += size;
+        while self.bits >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+// GIF flavor of LZW: codes grow from MIN_CODE_SIZE + 1 bits up to 12, the table being cleared
+// when it is full.
+fn compress(pixels: &[u8]) -> Vec<u8> {
+    let clear = 1u16 << MIN_CODE_SIZE;
+    let end = clear + 1;
+
+    let mut writer = BitWriter {
+        bytes: Vec::new(),
+        buffer: 0,
+        bits: 0,
+    };
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next = end + 1;
+    let mut size = MIN_CODE_SIZE + 1;
+    writer.write(clear, size);
+
+    let mut prefix = match pixels.first() {
+        Some(pixel) => *pixel as u16,
+        None => {
+            writer.write(end, size);
+            return writer.finish();
+        }
+    };
+    for pixel in pixels[1..].iter() {
+        if let Some(code) = table.get(&(prefix, *pixel)) {
+            prefix = *code;
+            continue;
+        }
+        writer.write(prefix, size);
+        if next < MAX_CODES {
+            table.insert((prefix, *pixel), next);
+            next += 1;
+            // The decoder only adds the code once it reads the next one, hence the lag
+            if next > 1 << size && size < 12 {
+                size += 1;
+            }
+        } else {
+            writer.write(clear, size);
+            table.clear();
+            next = end + 1;
+            size = MIN_CODE_SIZE + 1;
+        }
+        prefix = *pixel as u16;
+    }
+    writer.write(prefix, size);
+    // The decoder adds a code for the last one as well before reading the end
+    if next == 1 << size && size < 12 {
+        size += 1;
+    }
+    writer.write(end, size);
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GifRecorder;
+    use screenshot::Settings;
+
+    // Delays of the images, walking the blocks of the file.
+    fn delays(gif: &[u8]) -> Vec<u16> {
+        // Header, palette and loop extension
+        let mut pos = 13 + 12 + 19;
+        let mut delays = Vec::new();
+        while gif[pos] != 0x3B {
+            assert_eq!(&gif[pos..pos + 4], &[0x21, 0xF9, 4, 0]);
+            delays.push(gif[pos + 4] as u16 | (gif[pos + 5] as u16) << 8);
+            // Control extension, image descriptor and code size, then the data sub-blocks
+            pos += 8 + 10 + 1;
+            while gif[pos] != 0 {
+                pos += gif[pos] as usize + 1;
+            }
+            pos += 1;
+        }
+        delays
+    }
+
+    #[test]
+    fn short_images_are_merged() {
+        let mut recorder = GifRecorder::new(Vec::new(), &Settings::default());
+        for frame in 0..7 {
+            recorder.add_frame(&[frame % 2], 1).unwrap();
+        }
+        let gif = recorder.finish().unwrap();
+        assert_eq!(delays(&gif), vec![2, 3, 2, 3, 2]);
+    }
+
+    #[test]
+    fn identical_images_are_merged() {
+        let mut recorder = GifRecorder::new(Vec::new(), &Settings::default());
+        for frame in 0..60 {
+            recorder.add_frame(&[(frame / 30) as u8], 1).unwrap();
+        }
+        let gif = recorder.finish().unwrap();
+        assert_eq!(delays(&gif), vec![50, 50]);
+    }
+}
@@ -0,0 +1,142 @@
+use std::io;
+use std::fmt;
+use std::error::Error;
+
+use cpu;
+use com::Communicator;
+use com::input::InputCommunicator;
+use debugger;
+use movie;
+use screenshot;
+use {Options, create_audio_recorder, create_cpu, create_gif_recorder, debugger_step, report_fault,
+     save_screenshot};
+
+// When to stop a headless run, on top of SYS 0x100 (and EXIT) which always stop it.
+#[derive(Clone,Copy,Default)]
+pub struct Limits {
+    pub cycles: Option<u64>,
+    pub frames: Option<u64>,
+}
+
+impl Limits {
+    fn reached(&self, cpu: &cpu::CPU) -> bool {
+        self.cycles.is_some_and(|cycles| cpu.cycles() >= cycles) ||
+        self.frames.is_some_and(|frames| cpu.frames() >= frames)
+    }
+}
+
+// State of the machine at the end of a headless run.
+pub struct Outcome {
+    pub display: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+    pub registers: cpu::Registers,
+    pub cycles: u64,
+    pub frames: u64,
+}
+
+impl Outcome {
+    // Save the final display in the format matching the extension of the path.
+    pub fn save_screenshot(&self, path: &str, settings: &screenshot::Settings) -> io::Result<()> {
+        screenshot::save(path, &self.display, self.width, settings)
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "cycles: {}, frames: {}", self.cycles, self.frames)?;
+        write!(f, "{}", self.registers)?;
+        for line in self.display.chunks(self.width) {
+            let line: String = line.iter()
+                .map(|pixel| match *pixel {
+                         0 => '.',
+                         1 => '#',
+                         2 => '+',
+                         _ => '@',
+                     })
+                .collect();
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn run<T>(data: Vec<u8>,
+              options: &Options,
+              limits: Limits,
+              dump_file: &mut Option<T>)
+              -> Result<Outcome, Box<dyn Error>>
+    where T: io::Write
+{
+    let mut session = movie::Session::from_options(&data, options)?;
+    let mut cpu = create_cpu(data, options, session.as_ref());
+
+    // Nobody is there to press the keys, they only come from the movie being replayed
+    let mut communicator = Communicator::new();
+    let keypad = InputCommunicator::new();
+    let mut debugger = debugger::from_options(options)?;
+    let mut audio_recorder = create_audio_recorder(options)?;
+    let mut gif_recorder = match options.gif_file {
+        Some(ref path) => Some(create_gif_recorder(path, options)?),
+        None => None,
+    };
+    let mut fault = None;
+    while cpu.is_running() && !limits.reached(&cpu) {
+        if let Some(ref mut session) = session {
+            if session.finished(&cpu) {
+                break;
+            }
+            session.step(&cpu, &keypad, &mut communicator.input);
+        }
+        match debugger {
+            Some(ref mut debugger) => {
+                if debugger.quit_requested() {
+                    break;
+                }
+                if let Err(e) = debugger_step(debugger.as_mut(), &mut cpu, &mut communicator, options) {
+                    fault = Some(e);
+                    break;
+                }
+            }
+            None => {
+                if let Err(e) = cpu.step(&mut communicator) {
+                    fault = Some(cpu.fault(e));
+                    break;
+                }
+            }
+        }
+        if let Some(ref mut recorder) = audio_recorder {
+            recorder.record(&cpu, &communicator.audio)?;
+        }
+        if let Some(ref mut recorder) = gif_recorder {
+            recorder.record(&cpu, &communicator.video)?;
+        }
+    }
+
+    if let Some(ref mut session) = session {
+        session.finish(&cpu)?;
+    }
+    if let Some(recorder) = audio_recorder {
+        recorder.finish()?;
+    }
+    if let Some(recorder) = gif_recorder {
+        recorder.finish()?;
+    }
+    // Also saved after a crash, to see what happened
+    save_screenshot(&communicator.video, options)?;
+    if let Some(ref mut f) = *dump_file {
+        cpu.dump_memory(f)?;
+    }
+    if let Some(fault) = fault {
+        report_fault(&fault, options);
+        return Err(Box::new(fault));
+    }
+    Ok(Outcome {
+           display: communicator.video.display,
+           width: communicator.video.width,
+           height: communicator.video.height,
+           registers: cpu.registers(),
+           cycles: cpu.cycles(),
+           frames: cpu.frames(),
+       })
+}
@@ -1,54 +1,457 @@
+#[cfg(feature = "sdl")]
 extern crate sdl2;
 
 #[macro_use]
 extern crate log;
+extern crate rand;
 
-mod com;
-mod cpu;
-mod memory;
+pub mod com;
+pub mod cpu;
+pub mod memory;
+pub mod headless;
+pub mod debugger;
+pub mod asm;
+pub mod disasm;
+pub mod movie;
+pub mod rewind;
+pub mod clock;
+pub mod tone;
+pub mod wav;
+pub mod screenshot;
+pub mod gif;
+mod binary;
+#[cfg(feature = "sdl")]
 mod ui;
 
 use std::io;
+#[cfg(feature = "sdl")]
+use std::cmp;
+#[cfg(feature = "sdl")]
 use std::error::Error;
+#[cfg(feature = "sdl")]
+use std::mem;
+use std::fs::File;
+use std::io::BufWriter;
 use com::Communicator;
+use com::video::VideoCommunicator;
+#[cfg(feature = "sdl")]
+use com::input::InputCommunicator;
+pub use cpu::{Fault, Quirks, Registers, RndMode, SpriteMode, Timing};
+pub use memory::{RAM_SIZE, XO_RAM_SIZE};
 
-pub fn run<T>(data: Vec<u8>, dump_file: &mut Option<T>) -> Result<(), Box<Error>>
+pub struct Options {
+    pub quirks: Quirks,
+    pub ram_size: usize,
+    pub debug: bool,
+    pub gdb_port: Option<u16>,
+    // Where the save state hotkeys save and load the machine state
+    pub state_file: Option<String>,
+    // Seed of the RND instruction, random if None
+    pub seed: Option<u64>,
+    pub rnd_mode: RndMode,
+    // Movie of the keypad input to record or replay
+    pub movie: Option<movie::Mode>,
+    // Where the fault report is written if the ROM crashes
+    pub crash_file: Option<String>,
+    // Memory given to the rewind buffer of the window, in bytes (0 to disable it)
+    pub rewind_memory: usize,
+    // Instructions executed per second of emulated time, with the flat timing
+    pub clock_rate: u32,
+    pub timing: Timing,
+    // Pitch of the beeper in Hz, and its volume in percent
+    pub tone_frequency: u32,
+    pub volume: u8,
+    // Where the sound output is recorded, as a WAV file
+    pub audio_file: Option<String>,
+    // Where the display is saved when the emulator ends, and how
+    pub screenshot_file: Option<String>,
+    pub screenshot: screenshot::Settings,
+    // Start of the paths of the screenshots and GIF recordings started with the hotkeys
+    pub screenshot_prefix: Option<String>,
+    // Where the whole run is recorded as an animated GIF, with the screenshot settings
+    pub gif_file: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            quirks: Quirks::default(),
+            ram_size: RAM_SIZE,
+            debug: false,
+            gdb_port: None,
+            state_file: None,
+            seed: None,
+            rnd_mode: RndMode::default(),
+            movie: None,
+            crash_file: None,
+            rewind_memory: rewind::DEFAULT_MEMORY_LIMIT,
+            clock_rate: cpu::DEFAULT_CLOCK_RATE,
+            timing: Timing::default(),
+            tone_frequency: tone::DEFAULT_FREQUENCY,
+            volume: tone::DEFAULT_VOLUME,
+            audio_file: None,
+            screenshot_file: None,
+            screenshot: screenshot::Settings::default(),
+            screenshot_prefix: None,
+            gif_file: None,
+        }
+    }
+}
+
+// Create the CPU described by the options, with the ROM loaded into its memory. A movie is
+// replayed with the settings it was recorded with instead.
+fn create_cpu(data: Vec<u8>, options: &Options, session: Option<&movie::Session>) -> cpu::CPU {
+    if let Some(session) = session {
+        return session.movie().create_cpu(data);
+    }
+    let mut cpu = cpu::CPU::with_ram_size(options.ram_size);
+    cpu.set_quirks(options.quirks);
+    cpu.set_clock_rate(options.clock_rate);
+    cpu.set_timing(options.timing);
+    cpu.set_rnd_mode(options.rnd_mode);
+    if let Some(seed) = options.seed {
+        cpu.seed_rng(seed);
+    }
+    cpu.load_rom(data);
+    cpu
+}
+
+// Start recording the sound output, if the options ask for it.
+fn create_audio_recorder(options: &Options)
+                         -> io::Result<Option<wav::AudioRecorder<BufWriter<File>>>> {
+    match options.audio_file {
+        Some(ref path) => {
+            let out = BufWriter::new(File::create(path)?);
+            let recorder = wav::AudioRecorder::new(out, options.tone_frequency, options.volume)?;
+            Ok(Some(recorder))
+        }
+        None => Ok(None),
+    }
+}
+
+fn create_gif_recorder(path: &str,
+                       options: &Options)
+                       -> io::Result<gif::GifRecorder<BufWriter<File>>> {
+    let out = BufWriter::new(File::create(path)?);
+    Ok(gif::GifRecorder::new(out, &options.screenshot))
+}
+
+#[cfg(feature = "sdl")]
+pub fn run<T>(data: Vec<u8>,
+              options: &Options,
+              dump_file: &mut Option<T>)
+              -> Result<(), Box<dyn Error>>
     where T: io::Write
 {
     // Create the UI context (renderer, sound, window...)
-    let mut ui = ui::UiContext::new("TW-Chip8")?;
+    let mut ui = ui::UiContext::new("TW-Chip8", options)?;
 
     // Now create the CPU and load the ROM into memory
-    let mut cpu = cpu::CPU::new();
-    cpu.load_rom(data);
+    let mut session = movie::Session::from_options(&data, options)?;
+    let mut cpu = create_cpu(data, options, session.as_ref());
 
     // Create a communicator that will allow communication between the CPU and the UI
     let mut communicator = Communicator::new();
+    let mut keypad = InputCommunicator::new();
+
+    let mut debugger = debugger::from_options(options)?;
 
+    // Movies can't go back in time
+    let mut rewind = if options.rewind_memory > 0 && session.is_none() {
+        Some(rewind::Rewind::new(1, options.rewind_memory))
+    } else {
+        None
+    };
+
+    let mut audio_recorder = create_audio_recorder(options)?;
+    let mut gif_recorder = match options.gif_file {
+        Some(ref path) => Some(create_gif_recorder(path, options)?),
+        None => None,
+    };
+
+    let mut scheduler = clock::Scheduler::new();
+    let mut slow_motion = false;
+    let mut fault = None;
     let mut running = true;
     while running {
-        ui.update(&mut communicator);
-        cpu.step(&mut communicator)?;
-        if ui.events.quit || !cpu.is_running() {
-            running = false;
+        match session {
+            Some(_) => {
+                // The keys only reach the CPU through the movie, one frame at a time
+                mem::swap(&mut communicator.input, &mut keypad);
+                ui.update(&mut communicator);
+                mem::swap(&mut communicator.input, &mut keypad);
+            }
+            None => ui.update(&mut communicator),
+        }
+        let hotkeys = ui.events.immediate.hotkeys.clone();
+        let mut rewound = false;
+        for hotkey in hotkeys {
+            if let ui::Hotkey::Rewind = hotkey {
+                rewound = true;
+            }
+            match hotkey {
+                ui::Hotkey::SlowMotion => slow_motion = !slow_motion,
+                ui::Hotkey::Mute => ui.toggle_mute(),
+                ui::Hotkey::RecordGif => toggle_gif_recording(&mut gif_recorder, &cpu, options),
+                hotkey => {
+                    handle_hotkey(hotkey,
+                                  &mut cpu,
+                                  &mut communicator,
+                                  options,
+                                  &mut rewind,
+                                  session.is_some())
+                }
+            }
+        }
+        running = !ui.events.quit;
+
+        // The game stays paused while rewinding, or the next frame would undo the rewind
+        let paused = rewind.is_some() && (rewound || ui.events.rewinding);
+
+        // Run the CPU until the next frame, or until the debugger stops it
+        let frame = cpu.frames();
+        while running && !paused && cpu.frames() == frame {
+            if let Some(ref mut session) = session {
+                session.step(&cpu, &keypad, &mut communicator.input);
+            }
+            if let Some(ref mut rewind) = rewind {
+                rewind.record(&cpu, &communicator)?;
+            }
+            let stepped = match debugger {
+                Some(ref mut debugger) => {
+                    match debugger_step(debugger.as_mut(), &mut cpu, &mut communicator, options) {
+                        Ok(stepped) => stepped,
+                        Err(e) => {
+                            fault = Some(e);
+                            break;
+                        }
+                    }
+                }
+                None => {
+                    if let Err(e) = cpu.step(&mut communicator) {
+                        fault = Some(cpu.fault(e));
+                        break;
+                    }
+                    true
+                }
+            };
+            if let Some(ref mut recorder) = audio_recorder {
+                recorder.record(&cpu, &communicator.audio)?;
+            }
+            if let Some(ref mut recorder) = gif_recorder {
+                recorder.record(&cpu, &communicator.video)?;
+            }
+            if !cpu.is_running() ||
+               debugger.as_ref().is_some_and(|debugger| debugger.quit_requested()) ||
+               session.as_ref().is_some_and(|session| session.finished(&cpu)) {
+                running = false;
+            }
+            if !stepped {
+                break;
+            }
+        }
+        if fault.is_some() {
+            break;
+        }
+
+        // Rewinding goes as fast as the key repeats
+        if ui.events.turbo || (paused && rewound) {
+            scheduler.reset();
+        } else {
+            // A step may take several frames with the VIP timing
+            let frames = cmp::max(cpu.frames() - frame, 1);
+            let speed = if slow_motion { clock::SLOW_MOTION_SPEED } else { 1.0 };
+            scheduler.wait(speed / frames as f64);
         }
     }
 
+    if let Some(ref mut session) = session {
+        session.finish(&cpu)?;
+    }
+    if let Some(recorder) = audio_recorder {
+        recorder.finish()?;
+    }
+    if let Some(recorder) = gif_recorder {
+        recorder.finish()?;
+    }
+    save_screenshot(&communicator.video, options)?;
+
     if let Some(ref mut f) = *dump_file {
         cpu.dump_memory(f)?;
     }
-    Ok(())
+    match fault {
+        Some(fault) => {
+            report_fault(&fault, options);
+            Err(Box::new(fault))
+        }
+        None => Ok(()),
+    }
 }
 
-pub fn disassemble(data: Vec<u8>) {
-    let iter = data.chunks(2);
-    let mut addr = 0x200;
-    for bytes in iter {
-        let dword = (bytes[0] as u16 & 0xFF) << 8 | bytes[1] as u16 & 0xFF;
-        match cpu::inst::Instruction::from_binary(dword) {
-            Ok(inst) => println!("{:#X}\t| {}", addr, inst),
-            Err(_) => println!("{:#X}\t| NOP", addr),
+// Start recording a GIF next to the ROM, or stop the current recording.
+#[cfg(feature = "sdl")]
+fn toggle_gif_recording(recorder: &mut Option<gif::GifRecorder<BufWriter<File>>>,
+                        cpu: &cpu::CPU,
+                        options: &Options) {
+    if let Some(recorder) = recorder.take() {
+        match recorder.finish() {
+            Ok(_) => info!("GIF recording stopped."),
+            Err(e) => warn!("Can't finish the GIF recording: {}.", e),
+        }
+        return;
+    }
+
+    let path = match options.screenshot_prefix {
+        Some(ref prefix) => format!("{}-{}.gif", prefix, cpu.frames()),
+        None => {
+            warn!("No path to record the GIF to.");
+            return;
+        }
+    };
+    match create_gif_recorder(&path, options) {
+        Ok(gif) => {
+            info!("Recording a GIF to {}.", path);
+            *recorder = Some(gif);
+        }
+        Err(e) => warn!("Can't record a GIF to {}: {}.", path, e),
+    }
+}
+
+// Save the display to the screenshot file of the options, if any.
+fn save_screenshot(video: &VideoCommunicator, options: &Options) -> io::Result<()> {
+    match options.screenshot_file {
+        Some(ref path) => screenshot::save(path, &video.display, video.width, &options.screenshot),
+        None => Ok(()),
+    }
+}
+
+// Print the fault report to the standard error, and write it to the crash file if any.
+fn report_fault(fault: &cpu::Fault, options: &Options) {
+    let stderr = io::stderr();
+    if let Err(e) = fault.write_report(&mut stderr.lock()) {
+        warn!("Can't print the fault report: {}.", e);
+    }
+    if let Some(ref path) = options.crash_file {
+        if let Err(e) = File::create(path).and_then(|mut f| fault.write_report(&mut f)) {
+            warn!("Can't write the crash dump to {}: {}.", path, e);
         }
-        addr += 2;
     }
+}
+
+// Step through the debugger. The faults it pauses on are reported right away, the ones it quits
+// on are returned to end the run like without a debugger.
+fn debugger_step(debugger: &mut dyn debugger::Controller,
+                 cpu: &mut cpu::CPU,
+                 com: &mut Communicator,
+                 options: &Options)
+                 -> Result<bool, cpu::Fault> {
+    match debugger.step(cpu, com) {
+        Ok(stepped) => Ok(stepped),
+        Err(fault) => {
+            if debugger.quit_requested() {
+                return Err(fault);
+            }
+            report_fault(&fault, options);
+            Ok(false)
+        }
+    }
+}
+
+// The hotkeys don't stop the emulator on failure, the error is only logged.
+#[cfg(feature = "sdl")]
+fn handle_hotkey(hotkey: ui::Hotkey,
+                 cpu: &mut cpu::CPU,
+                 com: &mut Communicator,
+                 options: &Options,
+                 rewind: &mut Option<rewind::Rewind>,
+                 in_movie: bool) {
+    if let ui::Hotkey::Rewind = hotkey {
+        match *rewind {
+            Some(ref mut rewind) => {
+                match rewind.rewind(cpu, com) {
+                    Ok(true) => (),
+                    Ok(false) => info!("Nothing left to rewind."),
+                    Err(e) => warn!("Can't rewind: {}.", e),
+                }
+            }
+            None => warn!("Rewinding is disabled."),
+        }
+        return;
+    }
+    if let ui::Hotkey::Screenshot = hotkey {
+        // Named after the frame, so that each screenshot gets its own file
+        match options.screenshot_prefix {
+            Some(ref prefix) => {
+                let path = format!("{}-{}.png", prefix, cpu.frames());
+                let video = &com.video;
+                match screenshot::save(&path, &video.display, video.width, &options.screenshot) {
+                    Ok(()) => info!("Screenshot saved to {}.", path),
+                    Err(e) => warn!("Can't save the screenshot to {}: {}.", path, e),
+                }
+            }
+            None => warn!("No path to save the screenshot to."),
+        }
+        return;
+    }
+
+    let path = match options.state_file {
+        Some(ref path) => path,
+        None => {
+            warn!("No state file to save to or load from.");
+            return;
+        }
+    };
+    match hotkey {
+        ui::Hotkey::SaveState => {
+            match File::create(path).and_then(|mut f| cpu.save_state(com, &mut f)) {
+                Ok(()) => info!("State saved to {}.", path),
+                Err(e) => warn!("Can't save the state to {}: {}.", path, e),
+            }
+        }
+        ui::Hotkey::LoadState if in_movie => {
+            warn!("Can't load a state while recording or replaying a movie.");
+        }
+        ui::Hotkey::LoadState => {
+            let result = File::open(path)
+                .map_err(cpu::SnapshotError::from)
+                .and_then(|mut f| cpu.load_state(com, &mut f));
+            match result {
+                Ok(()) => {
+                    info!("State loaded from {}.", path);
+                    // The states taken so far belong to another timeline
+                    if let Some(ref mut rewind) = *rewind {
+                        rewind.clear();
+                    }
+                }
+                Err(e) => warn!("Can't load the state from {}: {}.", path, e),
+            }
+        }
+        ui::Hotkey::Rewind |
+        ui::Hotkey::Screenshot |
+        ui::Hotkey::RecordGif |
+        ui::Hotkey::SlowMotion |
+        ui::Hotkey::Mute => (),
+    }
+}
+
+// Print the instructions reachable from the entry point and the data around them.
+pub fn disassemble(data: Vec<u8>) {
+    print!("{}", disasm::Program::analyze(&data));
+}
+
+// Decode the whole ROM as instructions, two bytes at a time.
+pub fn disassemble_linear(data: Vec<u8>) {
+    print!("{}", disasm::Linear::new(&data));
+}
+
+// Write the control-flow graph of the ROM in the Graphviz DOT format.
+pub fn export_cfg<T>(data: Vec<u8>, out: &mut T) -> io::Result<()>
+    where T: io::Write
+{
+    let program = disasm::Program::analyze(&data);
+    write!(out, "{}", disasm::Graph::build(&program))
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, asm::AsmError> {
+    asm::assemble(source)
 }
\ No newline at end of file
@@ -5,15 +5,25 @@ use std::process;
 use std::error::Error;
 use std::fs::File;
 use std::io::prelude::*;
+use tw_chip8::{Options, Quirks, RndMode, Timing};
+use tw_chip8::headless::Limits;
+use tw_chip8::movie::Mode;
+use tw_chip8::screenshot::{self, Format};
 
 enum Action {
     Run(Option<String>),
+    Headless(Option<String>),
     Disassemble,
+    DisassembleLinear,
+    Cfg(String),
+    Assemble,
 }
 
 struct Config {
     action: Action,
     filename: String,
+    options: Options,
+    limits: Limits,
 }
 
 impl Config {
@@ -22,25 +32,147 @@ impl Config {
 
         let mut path: Option<String> = None;
         let mut action = Action::Run(None);
+        let mut options = Options::default();
+        let mut limits = Limits::default();
         for arg in args {
             match &arg[..] {
                 "--run" => action = Action::Run(None),
+                "--headless" => action = Action::Headless(None),
                 "--disassemble" => action = Action::Disassemble,
+                "--disassemble=flow" => action = Action::Disassemble,
+                "--disassemble=linear" => action = Action::DisassembleLinear,
+                "--assemble" => action = Action::Assemble,
+                s if s.starts_with("--cfg=") => action = Action::Cfg(String::from(&s[6..])),
+                s if s.starts_with("--quirks=") => {
+                    options.quirks = match Quirks::from_name(&s[9..]) {
+                        Some(q) => q,
+                        None => return Err("Unknown quirks profile."),
+                    }
+                },
+                s if s.starts_with("--seed=") => {
+                    options.seed = Some(s[7..].parse().map_err(|_| "Invalid seed.")?);
+                },
+                s if s.starts_with("--rnd=") => {
+                    options.rnd_mode = match RndMode::from_name(&s[6..]) {
+                        Some(mode) => mode,
+                        None => return Err("Unknown RND mode."),
+                    }
+                },
+                "--xo-chip" => options.ram_size = tw_chip8::XO_RAM_SIZE,
+                "--debug" => options.debug = true,
+                s if s.starts_with("--state=") => options.state_file = Some(String::from(&s[8..])),
+                s if s.starts_with("--crash=") => options.crash_file = Some(String::from(&s[8..])),
+                s if s.starts_with("--gdb=") => {
+                    options.gdb_port = Some(s[6..].parse().map_err(|_| "Invalid GDB port.")?);
+                },
+                s if s.starts_with("--record=") => {
+                    if let Some(Mode::Replay(_)) = options.movie {
+                        return Err("Cannot both record and replay a movie.");
+                    }
+                    options.movie = Some(Mode::Record(String::from(&s[9..])));
+                },
+                s if s.starts_with("--replay=") => {
+                    if let Some(Mode::Record(_)) = options.movie {
+                        return Err("Cannot both record and replay a movie.");
+                    }
+                    options.movie = Some(Mode::Replay(String::from(&s[9..])));
+                },
+                s if s.starts_with("--rewind=") => {
+                    let megabytes: usize = s[9..].parse().map_err(|_| "Invalid rewind memory.")?;
+                    options.rewind_memory = megabytes.checked_mul(1024 * 1024)
+                        .ok_or("Invalid rewind memory.")?;
+                },
+                s if s.starts_with("--ips=") => {
+                    options.clock_rate = s[6..].parse().map_err(|_| "Invalid clock rate.")?;
+                    if options.clock_rate < tw_chip8::cpu::TIMER_FREQUENCY {
+                        return Err("The clock rate must be at least 60 instructions per second.");
+                    }
+                },
+                s if s.starts_with("--cycles-per-frame=") => {
+                    let cycles: u32 = s[19..].parse().map_err(|_| "Invalid cycles per frame.")?;
+                    if cycles == 0 {
+                        return Err("There must be at least one cycle per frame.");
+                    }
+                    options.clock_rate = cycles.checked_mul(tw_chip8::cpu::TIMER_FREQUENCY)
+                        .ok_or("Invalid cycles per frame.")?;
+                },
+                s if s.starts_with("--timing=") => {
+                    options.timing = match Timing::from_name(&s[9..]) {
+                        Some(timing) => timing,
+                        None => return Err("Unknown timing model."),
+                    }
+                },
+                s if s.starts_with("--tone=") => {
+                    options.tone_frequency = s[7..].parse().map_err(|_| "Invalid tone frequency.")?;
+                },
+                s if s.starts_with("--record-audio=") => {
+                    options.audio_file = Some(String::from(&s[15..]));
+                },
+                s if s.starts_with("--screenshot=") => {
+                    if Format::from_path(&s[13..]).is_none() {
+                        return Err("Unknown screenshot format, use .pbm, .pgm or .png.");
+                    }
+                    options.screenshot_file = Some(String::from(&s[13..]));
+                },
+                s if s.starts_with("--record-gif=") => {
+                    options.gif_file = Some(String::from(&s[13..]));
+                },
+                s if s.starts_with("--scale=") => {
+                    options.screenshot.scale = s[8..].parse().map_err(|_| "Invalid scale.")?;
+                    if options.screenshot.scale == 0 {
+                        return Err("The scale must be at least 1.");
+                    }
+                },
+                s if s.starts_with("--palette=") => {
+                    options.screenshot.palette = match screenshot::parse_palette(&s[10..]) {
+                        Some(palette) => palette,
+                        None => return Err("Invalid palette."),
+                    }
+                },
+                s if s.starts_with("--volume=") => {
+                    options.volume = s[9..].parse().map_err(|_| "Invalid volume.")?;
+                    if options.volume > 100 {
+                        return Err("The volume must be between 0 and 100.");
+                    }
+                },
+                s if s.starts_with("--cycles=") => {
+                    limits.cycles = Some(s[9..].parse().map_err(|_| "Invalid cycle count.")?);
+                },
+                s if s.starts_with("--frames=") => {
+                    limits.frames = Some(s[9..].parse().map_err(|_| "Invalid frame count.")?);
+                },
                 s if s.starts_with("--dump=") => {
                     match action {
-                        Action::Run(ref mut path) => *path = Some(String::from(&s[7..])),
-                        Action::Disassemble => return Err("Cannot dump on disassemble."),
+                        Action::Run(ref mut path) |
+                        Action::Headless(ref mut path) => *path = Some(String::from(&s[7..])),
+                        Action::Disassemble |
+                        Action::DisassembleLinear |
+                        Action::Cfg(_) |
+                        Action::Assemble => return Err("Can only dump when running a ROM."),
                     }
                 },
                 s => path = Some(String::from(s)),
             };
         }
 
+        if options.debug && options.gdb_port.is_some() {
+            return Err("Cannot use both --debug and --gdb.");
+        }
+
         match path {
-            Some(p) => Ok(Config {
-                action: action,
-                filename: p,
-            }),
+            Some(p) => {
+                // Save states and screenshots go next to the ROM by default
+                if options.state_file.is_none() {
+                    options.state_file = Some(format!("{}.state", p));
+                }
+                options.screenshot_prefix = Some(p.clone());
+                Ok(Config {
+                    action,
+                    filename: p,
+                    options,
+                    limits,
+                })
+            },
             None => Err("ROM file needed.")
         }
     }
@@ -67,15 +199,53 @@ fn run(config: Config) -> Result<(), Box<Error>> {
 
     match config.action {
         Action::Run(dump_file) => {
-            let mut f = match dump_file {
-                Some(path) => {
-                    let file = File::create(path)?;
-                    Some(file)
-                },
-                None => None,
-            };
-            tw_chip8::run(data, &mut f)
+            let mut f = create_file(dump_file)?;
+            run_window(data, &config.options, &mut f)
+        },
+        Action::Headless(dump_file) => {
+            let mut f = create_file(dump_file)?;
+            let outcome = tw_chip8::headless::run(data, &config.options, config.limits, &mut f)?;
+            print!("{}", outcome);
+            Ok(())
         },
         Action::Disassemble => Ok(tw_chip8::disassemble(data)),
+        Action::DisassembleLinear => {
+            tw_chip8::disassemble_linear(data);
+            Ok(())
+        },
+        Action::Cfg(path) => {
+            let mut f = File::create(path)?;
+            tw_chip8::export_cfg(data, &mut f)?;
+            Ok(())
+        },
+        Action::Assemble => {
+            let source = String::from_utf8(data)?;
+            let rom = tw_chip8::assemble(&source)?;
+            std::io::stdout().write_all(&rom)?;
+            Ok(())
+        },
+    }
+}
+
+#[cfg(feature = "sdl")]
+fn run_window(data: Vec<u8>,
+              options: &Options,
+              dump_file: &mut Option<File>)
+              -> Result<(), Box<dyn Error>> {
+    tw_chip8::run(data, options, dump_file)
+}
+
+#[cfg(not(feature = "sdl"))]
+fn run_window(_: Vec<u8>, _: &Options, _: &mut Option<File>) -> Result<(), Box<dyn Error>> {
+    Err(From::from("built without SDL support, use --headless"))
+}
+
+fn create_file(path: Option<String>) -> Result<Option<File>, Box<dyn Error>> {
+    match path {
+        Some(path) => {
+            let file = File::create(path)?;
+            Ok(Some(file))
+        },
+        None => Ok(None),
     }
 }
\ No newline at end of file
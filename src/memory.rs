@@ -2,7 +2,11 @@ use std::io;
 use std::fmt;
 use std::error::Error;
 
-const RAM_SIZE: usize = 0x1000;
+pub const RAM_SIZE: usize = 0x1000;
+pub const XO_RAM_SIZE: usize = 0x10000;
+
+pub const FONT_ADDRESS: usize = 0x150;
+pub const BIG_FONT_ADDRESS: usize = 0x0B0;
 
 static HEX_DIGITS: [u8; 80] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
@@ -23,6 +27,25 @@ static HEX_DIGITS: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80  // F
 ];
 
+static BIG_HEX_DIGITS: [u8; 160] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0  // F
+];
+
 #[derive(Debug)]
 pub enum MemoryError {
     ReservedAddress(usize),
@@ -52,15 +75,22 @@ impl Error for MemoryError {
 }
 
 pub struct Memory {
-    ram: [u8; RAM_SIZE],
+    ram: Vec<u8>,
 }
 
 impl Memory {
-    pub fn new() -> Memory {
-        let mut ram = [0u8; RAM_SIZE];
+    pub fn with_size(size: usize) -> Memory {
+        let mut ram = vec![0u8; size];
         {
             let hex_iter = HEX_DIGITS.iter();
-            let iter = ram.iter_mut().skip(0x150).zip(hex_iter);
+            let iter = ram.iter_mut().skip(FONT_ADDRESS).zip(hex_iter);
+            for (src, dst) in iter {
+                *src = *dst;
+            }
+        }
+        {
+            let hex_iter = BIG_HEX_DIGITS.iter();
+            let iter = ram.iter_mut().skip(BIG_FONT_ADDRESS).zip(hex_iter);
             for (src, dst) in iter {
                 *src = *dst;
             }
@@ -68,8 +98,18 @@ impl Memory {
         Memory { ram: ram }
     }
 
+    // Memory holding exactly the given content, fonts included.
+    pub fn from_ram(ram: Vec<u8>) -> Memory {
+        Memory { ram }
+    }
+
+    // Whole content of the memory, reserved area included.
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
     pub fn load_rom(&mut self, rom: Vec<u8>) {
-        let rom_iter = rom.iter().take(RAM_SIZE - 0x200);
+        let rom_iter = rom.iter().take(self.ram.len() - 0x200);
         let iter = self.ram.iter_mut().skip(0x200).zip(rom_iter);
         for (src, dst) in iter {
             *src = *dst;
@@ -84,7 +124,7 @@ impl Memory {
 
     pub fn read_word(&self, addr: usize) -> Result<u8, MemoryError> {
         match addr {
-            _ if addr > 0xFFF => Err(MemoryError::UnmappedAddress(addr)),
+            _ if addr >= self.ram.len() => Err(MemoryError::UnmappedAddress(addr)),
             _ => Ok(self.ram[addr]),
         }
     }
@@ -92,7 +132,7 @@ impl Memory {
     pub fn write_word(&mut self, addr: usize, b: u8) -> Result<(), MemoryError> {
         match addr {
             _ if addr < 0x200 => Err(MemoryError::ReservedAddress(addr)),
-            _ if addr > 0xFFF => Err(MemoryError::UnmappedAddress(addr)),
+            _ if addr >= self.ram.len() => Err(MemoryError::UnmappedAddress(addr)),
             _ => {
                 self.ram[addr] = b;
                 Ok(())
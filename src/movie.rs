@@ -0,0 +1,459 @@
+// Input movies: the keypad state of every 60 Hz frame, along with everything else the run
+// depends on, so that a session can be replayed exactly. Everything is big-endian, after a magic
+// header and a format version:
+//
+//     "TW-MOVIE" version:u16
+//     rom_hash:u64 quirks:u8 sprite_mode:u8 ram_size:u32 clock_rate:u32 timing:u8 rnd_mode:u8
+//     seed:u64
+//     cycles:u64 frame_count:u32 keys:u16[frame_count]
+//
+// The ROM hash is the 64-bit FNV-1a of the ROM. The keys are bitmasks, bit N being key N, and
+// only change the keypad state at the start of each frame. Cycles is the number of steps the
+// recording lasted, where the replay stops.
+
+use std::io;
+use std::fmt;
+use std::error::Error;
+use std::fs::File;
+
+use rand;
+use cpu::{CPU, Quirks, RndMode, SpriteMode, Timing};
+use com::input::InputCommunicator;
+use memory;
+use binary::{write_u16, write_u32, write_u64, read_u8, read_u16, read_u32, read_u64};
+use Options;
+
+const MAGIC: &[u8; 8] = b"TW-MOVIE";
+const VERSION: u16 = 3;
+
+#[derive(Debug)]
+pub enum MovieError {
+    IoError(io::Error),
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    InvalidMovie(&'static str),
+    RomMismatch,
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MovieError::IoError(ref err) => write!(f, "I/O error: {}", err),
+            MovieError::InvalidMagic => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion(version) => {
+                write!(f, "unsupported movie version {}", version)
+            }
+            MovieError::InvalidMovie(reason) => write!(f, "invalid movie: {}", reason),
+            MovieError::RomMismatch => write!(f, "the movie was recorded with another ROM"),
+        }
+    }
+}
+
+impl Error for MovieError {
+    fn description(&self) -> &str {
+        match *self {
+            MovieError::IoError(_) => "I/O error",
+            MovieError::InvalidMagic => "not a movie",
+            MovieError::UnsupportedVersion(_) => "unsupported movie version",
+            MovieError::InvalidMovie(_) => "invalid movie",
+            MovieError::RomMismatch => "the movie was recorded with another ROM",
+        }
+    }
+
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            MovieError::IoError(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MovieError {
+    fn from(err: io::Error) -> MovieError {
+        MovieError::IoError(err)
+    }
+}
+
+// Whether the movie file of the options is written or read.
+#[derive(Clone)]
+pub enum Mode {
+    Record(String),
+    Replay(String),
+}
+
+pub struct Movie {
+    pub rom_hash: u64,
+    pub quirks: Quirks,
+    pub ram_size: usize,
+    pub clock_rate: u32,
+    pub timing: Timing,
+    pub rnd_mode: RndMode,
+    pub seed: u64,
+    pub cycles: u64,
+    pub keys: Vec<u16>,
+}
+
+impl Movie {
+    // An empty movie of the ROM with the given options. Without a seed in the options, a random
+    // one is picked so that the replay gets the same random numbers.
+    pub fn new(rom: &[u8], options: &Options) -> Movie {
+        Movie {
+            rom_hash: rom_hash(rom),
+            quirks: options.quirks,
+            ram_size: options.ram_size,
+            clock_rate: options.clock_rate,
+            timing: options.timing,
+            rnd_mode: options.rnd_mode,
+            seed: options.seed.unwrap_or_else(rand::random),
+            cycles: 0,
+            keys: Vec::new(),
+        }
+    }
+
+    // Create the CPU the movie was recorded with, with the ROM loaded into its memory.
+    pub fn create_cpu(&self, rom: Vec<u8>) -> CPU {
+        let mut cpu = CPU::with_ram_size(self.ram_size);
+        cpu.set_quirks(self.quirks);
+        cpu.set_clock_rate(self.clock_rate);
+        cpu.set_timing(self.timing);
+        cpu.set_rnd_mode(self.rnd_mode);
+        cpu.seed_rng(self.seed);
+        cpu.load_rom(rom);
+        cpu
+    }
+
+    pub fn check_rom(&self, rom: &[u8]) -> Result<(), MovieError> {
+        if rom_hash(rom) != self.rom_hash {
+            return Err(MovieError::RomMismatch);
+        }
+        Ok(())
+    }
+
+    pub fn write<W>(&self, out: &mut W) -> io::Result<()>
+        where W: io::Write
+    {
+        out.write_all(MAGIC)?;
+        write_u16(out, VERSION)?;
+
+        write_u64(out, self.rom_hash)?;
+        let (flags, sprite_mode) = encode_quirks(&self.quirks);
+        out.write_all(&[flags, sprite_mode])?;
+        write_u32(out, self.ram_size as u32)?;
+        write_u32(out, self.clock_rate)?;
+        let timing = match self.timing {
+            Timing::Flat => 0,
+            Timing::CosmacVip => 1,
+        };
+        let rnd_mode = match self.rnd_mode {
+            RndMode::Xorshift => 0,
+            RndMode::CosmacVip => 1,
+        };
+        out.write_all(&[timing, rnd_mode])?;
+        write_u64(out, self.seed)?;
+
+        write_u64(out, self.cycles)?;
+        write_u32(out, self.keys.len() as u32)?;
+        for keys in self.keys.iter() {
+            write_u16(out, *keys)?;
+        }
+        Ok(())
+    }
+
+    pub fn read<R>(input: &mut R) -> Result<Movie, MovieError>
+        where R: io::Read
+    {
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(MovieError::InvalidMagic);
+        }
+        let version = read_u16(input)?;
+        if version != VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+
+        let rom_hash = read_u64(input)?;
+        let flags = read_u8(input)?;
+        let sprite_mode = read_u8(input)?;
+        let quirks = decode_quirks(flags, sprite_mode)
+            .ok_or(MovieError::InvalidMovie("unknown quirks"))?;
+        let ram_size = read_u32(input)? as usize;
+        if !(memory::RAM_SIZE..=memory::XO_RAM_SIZE).contains(&ram_size) {
+            return Err(MovieError::InvalidMovie("unsupported memory size"));
+        }
+        let clock_rate = read_u32(input)?;
+        let timing = match read_u8(input)? {
+            0 => Timing::Flat,
+            1 => Timing::CosmacVip,
+            _ => return Err(MovieError::InvalidMovie("unknown timing")),
+        };
+        let rnd_mode = match read_u8(input)? {
+            0 => RndMode::Xorshift,
+            1 => RndMode::CosmacVip,
+            _ => return Err(MovieError::InvalidMovie("unknown RND mode")),
+        };
+        let seed = read_u64(input)?;
+
+        let cycles = read_u64(input)?;
+        let frame_count = read_u32(input)?;
+        let mut keys = Vec::new();
+        for _ in 0..frame_count {
+            keys.push(read_u16(input)?);
+        }
+
+        Ok(Movie {
+               rom_hash,
+               quirks,
+               ram_size,
+               clock_rate,
+               timing,
+               rnd_mode,
+               seed,
+               cycles,
+               keys,
+           })
+    }
+}
+
+// A movie being recorded or replayed during a run.
+pub struct Session {
+    movie: Movie,
+    // Where the movie is saved, when recording
+    path: Option<String>,
+    // Frame whose keys were last applied
+    frame: Option<u64>,
+}
+
+impl Session {
+    // Start recording or replaying the movie of the options, if any. A replay is refused if the
+    // movie was recorded with another ROM.
+    pub fn from_options(rom: &[u8], options: &Options) -> Result<Option<Session>, MovieError> {
+        let (movie, path) = match options.movie {
+            Some(Mode::Record(ref path)) => (Movie::new(rom, options), Some(path.clone())),
+            Some(Mode::Replay(ref path)) => {
+                let movie = Movie::read(&mut File::open(path)?)?;
+                movie.check_rom(rom)?;
+                (movie, None)
+            }
+            None => return Ok(None),
+        };
+        Ok(Some(Session {
+                    movie,
+                    path,
+                    frame: None,
+                }))
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    // Call before each step. At the start of a frame, the keys held on the keypad are recorded,
+    // or replaced with the recorded ones, and become the input of the CPU for the whole frame.
+    pub fn step(&mut self, cpu: &CPU, keypad: &InputCommunicator, input: &mut InputCommunicator) {
+        let frame = cpu.frames();
+        if self.frame == Some(frame) {
+            return;
+        }
+        self.frame = Some(frame);
+
+        let keys = if self.path.is_some() {
+            // With the VIP timing, a step may take several frames, which get the same keys
+            let keys = keypad.state();
+            while self.movie.keys.len() as u64 <= frame {
+                self.movie.keys.push(keys);
+            }
+            keys
+        } else {
+            self.movie.keys.get(frame as usize).cloned().unwrap_or(0)
+        };
+        input.set_state(keys);
+    }
+
+    // Whether a replay reached the point where the recording stopped.
+    pub fn finished(&self, cpu: &CPU) -> bool {
+        self.path.is_none() && cpu.cycles() >= self.movie.cycles
+    }
+
+    // End the session, saving the movie if it was being recorded.
+    pub fn finish(&mut self, cpu: &CPU) -> io::Result<()> {
+        match self.path {
+            Some(ref path) => {
+                self.movie.cycles = cpu.cycles();
+                self.movie.write(&mut File::create(path)?)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+// 64-bit FNV-1a.
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    rom.iter().fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01B3)
+    })
+}
+
+fn encode_quirks(quirks: &Quirks) -> (u8, u8) {
+    let flags = [quirks.shift_vy,
+                 quirks.load_store_increment_i,
+                 quirks.logic_reset_vf,
+                 quirks.jump_vx,
+                 quirks.display_wait,
+                 quirks.load_store_increment_i_by_x];
+    let flags = flags.iter().enumerate().fold(0u8, |acc, (bit, set)| acc | (*set as u8) << bit);
+    let sprite_mode = match quirks.sprite_mode {
+        SpriteMode::Wrap => 0,
+        SpriteMode::Clip => 1,
+    };
+    (flags, sprite_mode)
+}
+
+fn decode_quirks(flags: u8, sprite_mode: u8) -> Option<Quirks> {
+    if flags >> 6 != 0 {
+        return None;
+    }
+    let sprite_mode = match sprite_mode {
+        0 => SpriteMode::Wrap,
+        1 => SpriteMode::Clip,
+        _ => return None,
+    };
+    Some(Quirks {
+             shift_vy: flags & 0x01 != 0,
+             load_store_increment_i: flags & 0x02 != 0,
+             logic_reset_vf: flags & 0x04 != 0,
+             jump_vx: flags & 0x08 != 0,
+             display_wait: flags & 0x10 != 0,
+             load_store_increment_i_by_x: flags & 0x20 != 0,
+             sprite_mode,
+         })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::process;
+
+    use super::{Movie, MovieError, Mode, Session, encode_quirks, decode_quirks};
+    use cpu::{CPU, Quirks, SpriteMode};
+    use com::Communicator;
+    use com::input::InputCommunicator;
+    use Options;
+
+    // Draws the digit of every key held, moving right and down to a random row after each scan
+    // of the keypad
+    const ROM: [u8; 26] = [0x61, 0x00, 0x62, 0x00, 0x60, 0x00, 0xE0, 0x9E, 0x12, 0x0E, 0xF0,
+                           0x29, 0xD1, 0x25, 0x70, 0x01, 0x30, 0x10, 0x12, 0x06, 0x71, 0x08,
+                           0xC2, 0x1F, 0x12, 0x04];
+
+    // Run until the session is finished, or for the given frames, the keypad changing every few
+    // frames following the pattern.
+    fn run(cpu: &mut CPU, session: &mut Session, frames: u64, pattern: u64) -> Communicator {
+        let mut com = Communicator::new();
+        let mut keypad = InputCommunicator::new();
+        while cpu.frames() < frames && !session.finished(cpu) {
+            keypad.set_state((cpu.frames() / 5).wrapping_mul(pattern) as u16);
+            session.step(cpu, &keypad, &mut com.input);
+            cpu.step(&mut com).unwrap();
+        }
+        com
+    }
+
+    fn record(options: &Options) -> (Movie, Communicator) {
+        let mut session = Session {
+            movie: Movie::new(&ROM, options),
+            path: Some(String::new()),
+            frame: None,
+        };
+        let mut cpu = session.movie.create_cpu(ROM.to_vec());
+        let com = run(&mut cpu, &mut session, 300, 0x9E37);
+        session.movie.cycles = cpu.cycles();
+        (session.movie, com)
+    }
+
+    #[test]
+    fn replay_ends_with_the_same_display() {
+        let options = Options { quirks: Quirks::chip48(), ..Options::default() };
+        let (movie, recorded) = record(&options);
+        assert!(recorded.video.display.iter().any(|pixel| *pixel != 0));
+
+        let mut data = Vec::new();
+        movie.write(&mut data).unwrap();
+        let movie = Movie::read(&mut &data[..]).unwrap();
+        assert!(movie.quirks == Quirks::chip48());
+        movie.check_rom(&ROM).unwrap();
+
+        let mut cpu = movie.create_cpu(ROM.to_vec());
+        let mut session = Session {
+            movie,
+            path: None,
+            frame: None,
+        };
+        // The keys held on the keypad are replaced with the recorded ones
+        let replayed = run(&mut cpu, &mut session, u64::MAX, 0x1234);
+        assert_eq!(cpu.cycles(), session.movie.cycles);
+        assert_eq!(replayed.video.display, recorded.video.display);
+    }
+
+    #[test]
+    fn replay_refuses_another_rom() {
+        let (movie, _) = record(&Options::default());
+        let path = env::temp_dir().join(format!("tw-chip8-movie-test-{}", process::id()));
+        movie.write(&mut File::create(&path).unwrap()).unwrap();
+
+        let options = Options {
+            movie: Some(Mode::Replay(path.to_string_lossy().into_owned())),
+            ..Options::default()
+        };
+        let mut other = ROM.to_vec();
+        other[1] = 0x01;
+        let result = Session::from_options(&other, &options);
+        let accepted = Session::from_options(&ROM, &options);
+        fs::remove_file(&path).unwrap();
+
+        match result {
+            Err(MovieError::RomMismatch) => {},
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("a movie of another ROM was accepted"),
+        }
+        assert!(accepted.unwrap().is_some());
+    }
+
+    #[test]
+    fn quirks_round_trip() {
+        let none = Quirks {
+            shift_vy: false,
+            load_store_increment_i: false,
+            load_store_increment_i_by_x: false,
+            logic_reset_vf: false,
+            jump_vx: false,
+            display_wait: false,
+            sprite_mode: SpriteMode::Wrap,
+        };
+        let mut each = Vec::new();
+        for flag in 0..6 {
+            let mut quirks = none;
+            let set = match flag {
+                0 => &mut quirks.shift_vy,
+                1 => &mut quirks.load_store_increment_i,
+                2 => &mut quirks.logic_reset_vf,
+                3 => &mut quirks.jump_vx,
+                4 => &mut quirks.display_wait,
+                _ => &mut quirks.load_store_increment_i_by_x,
+            };
+            *set = true;
+            assert_eq!(encode_quirks(&quirks), (1 << flag, 0));
+            each.push(quirks);
+        }
+        each.push(Quirks { sprite_mode: SpriteMode::Clip, ..none });
+        each.extend_from_slice(&[none, Quirks::cosmac_vip(), Quirks::chip48(), Quirks::schip()]);
+
+        for quirks in each {
+            let (flags, sprite_mode) = encode_quirks(&quirks);
+            assert!(decode_quirks(flags, sprite_mode) == Some(quirks));
+        }
+        assert!(decode_quirks(0x40, 0).is_none());
+        assert!(decode_quirks(0, 2).is_none());
+    }
+}
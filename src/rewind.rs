@@ -0,0 +1,256 @@
+// Rewind buffer: save states taken every few frames, the oldest ones being dropped to stay under
+// a memory limit. Consecutive states barely differ (a few registers, some memory and display
+// bytes), so only one state in KEYFRAME_INTERVAL is kept whole, a keyframe, and the following
+// ones as the bytes that differ from it:
+//
+//     (unchanged:u32 changed:u32 bytes:u8[changed])*
+//
+// A state whose size differs from its keyframe (after a resolution change) becomes a keyframe.
+
+use std::io;
+use std::collections::VecDeque;
+
+use cpu::{CPU, SnapshotError};
+use com::Communicator;
+use binary::{write_u32, read_u32};
+
+// States kept as the difference with the previous keyframe, between two keyframes.
+pub const KEYFRAME_INTERVAL: usize = 60;
+
+// Size of the unchanged and changed counts of a delta run.
+const RUN_HEADER_SIZE: usize = 8;
+
+// Memory used by the rewind buffer by default, in bytes.
+pub const DEFAULT_MEMORY_LIMIT: usize = 8 * 1024 * 1024;
+
+enum Data {
+    Keyframe(Vec<u8>),
+    Delta(Vec<u8>),
+}
+
+struct Entry {
+    frame: u64,
+    data: Data,
+}
+
+impl Entry {
+    fn is_keyframe(&self) -> bool {
+        match self.data {
+            Data::Keyframe(_) => true,
+            Data::Delta(_) => false,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self.data {
+            Data::Keyframe(ref state) |
+            Data::Delta(ref state) => state.len(),
+        }
+    }
+}
+
+pub struct Rewind {
+    // Frames between two states
+    interval: u64,
+    memory_limit: usize,
+    entries: VecDeque<Entry>,
+    // Index of the last keyframe in the entries
+    keyframe: Option<usize>,
+    used: usize,
+    // Frame of the last state taken or restored
+    last_frame: Option<u64>,
+}
+
+impl Rewind {
+    pub fn new(interval: u64, memory_limit: usize) -> Rewind {
+        Rewind {
+            interval,
+            memory_limit,
+            entries: VecDeque::new(),
+            keyframe: None,
+            used: 0,
+            last_frame: None,
+        }
+    }
+
+    // Call before each step, a state is taken at the start of every interval.
+    pub fn record(&mut self, cpu: &CPU, com: &Communicator) -> io::Result<()> {
+        let frame = cpu.frames();
+        if self.last_frame.is_some_and(|last| frame < last + self.interval && frame >= last) {
+            return Ok(());
+        }
+        self.last_frame = Some(frame);
+
+        let mut state = Vec::new();
+        cpu.save_state(com, &mut state)?;
+        let delta = match self.keyframe {
+            Some(index) if self.entries.len() - index < KEYFRAME_INTERVAL => {
+                match self.entries[index].data {
+                    Data::Keyframe(ref keyframe) if keyframe.len() == state.len() => {
+                        Some(diff(keyframe, &state)?)
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        let data = match delta {
+            Some(delta) => Data::Delta(delta),
+            None => {
+                self.keyframe = Some(self.entries.len());
+                Data::Keyframe(state)
+            }
+        };
+        let entry = Entry { frame, data };
+        self.used += entry.size();
+        self.entries.push_back(entry);
+        self.evict();
+        Ok(())
+    }
+
+    // Go back to the last state taken before the current frame, which stays in the buffer.
+    // Returns false if there is no such state.
+    pub fn rewind(&mut self, cpu: &mut CPU, com: &mut Communicator) -> Result<bool, SnapshotError> {
+        let frame = cpu.frames();
+        while self.entries.back().is_some_and(|entry| entry.frame >= frame) {
+            self.pop();
+        }
+
+        if self.entries.is_empty() {
+            return Ok(false);
+        }
+        let state = self.last_state()?;
+        cpu.load_state(com, &mut &state[..])?;
+        self.last_frame = Some(cpu.frames());
+        Ok(true)
+    }
+
+    // Number of states in the buffer.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Memory used by the states, in bytes.
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.keyframe = None;
+        self.used = 0;
+        self.last_frame = None;
+    }
+
+    // Rebuild the whole state of the last entry.
+    fn last_state(&self) -> io::Result<Vec<u8>> {
+        let keyframe = match self.entries[self.keyframe.unwrap()].data {
+            Data::Keyframe(ref keyframe) => keyframe,
+            Data::Delta(_) => unreachable!(),
+        };
+        match self.entries.back().unwrap().data {
+            Data::Keyframe(ref state) => Ok(state.clone()),
+            Data::Delta(ref delta) => patch(keyframe, delta),
+        }
+    }
+
+    fn pop(&mut self) {
+        if let Some(entry) = self.entries.pop_back() {
+            self.used -= entry.size();
+            if entry.is_keyframe() {
+                self.keyframe = self.entries.iter().rposition(Entry::is_keyframe);
+            }
+        }
+    }
+
+    // Drop the oldest keyframes along with their deltas, always keeping the last one.
+    fn evict(&mut self) {
+        while self.used > self.memory_limit && self.keyframe.is_some_and(|index| index > 0) {
+            let count = self.entries
+                .iter()
+                .skip(1)
+                .position(Entry::is_keyframe)
+                .unwrap() + 1;
+            for entry in self.entries.drain(..count) {
+                self.used -= entry.size();
+            }
+            self.keyframe = self.keyframe.map(|index| index - count);
+        }
+    }
+}
+
+fn diff(keyframe: &[u8], state: &[u8]) -> io::Result<Vec<u8>> {
+    let mut delta = Vec::new();
+    let mut pos = 0;
+    while pos < state.len() {
+        let start = pos;
+        while pos < state.len() && state[pos] == keyframe[pos] {
+            pos += 1;
+        }
+        let changed_start = pos;
+
+        // Short unchanged runs are cheaper to copy than to start a new run for
+        let mut changed_end = pos;
+        while pos < state.len() && pos - changed_end < RUN_HEADER_SIZE {
+            pos += 1;
+            if state[pos - 1] != keyframe[pos - 1] {
+                changed_end = pos;
+            }
+        }
+        pos = changed_end;
+
+        write_u32(&mut delta, (changed_start - start) as u32)?;
+        write_u32(&mut delta, (changed_end - changed_start) as u32)?;
+        delta.extend_from_slice(&state[changed_start..changed_end]);
+    }
+    Ok(delta)
+}
+
+fn patch(keyframe: &[u8], delta: &[u8]) -> io::Result<Vec<u8>> {
+    let mut state = keyframe.to_vec();
+    let mut input = delta;
+    let mut pos = 0;
+    while !input.is_empty() {
+        pos += read_u32(&mut input)? as usize;
+        let changed = read_u32(&mut input)? as usize;
+        if pos + changed > state.len() || changed > input.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "corrupted rewind state"));
+        }
+        state[pos..pos + changed].copy_from_slice(&input[..changed]);
+        input = &input[changed..];
+        pos += changed;
+    }
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rewind, DEFAULT_MEMORY_LIMIT};
+    use cpu::CPU;
+    use com::Communicator;
+
+    #[test]
+    fn rewinds_one_frame_at_a_time() {
+        let mut cpu = CPU::new();
+        // JP 0x200
+        cpu.load_rom(vec![0x12, 0x00]);
+        let mut com = Communicator::new();
+        let mut rewind = Rewind::new(1, DEFAULT_MEMORY_LIMIT);
+        while cpu.frames() < 100 {
+            rewind.record(&cpu, &com).unwrap();
+            cpu.step(&mut com).unwrap();
+        }
+
+        // Across a keyframe
+        for count in 1..71 {
+            assert!(rewind.rewind(&mut cpu, &mut com).unwrap());
+            assert_eq!(cpu.frames(), 100 - count);
+        }
+        while rewind.rewind(&mut cpu, &mut com).unwrap() {}
+        assert_eq!(cpu.frames(), 0);
+    }
+}
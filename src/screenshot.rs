@@ -0,0 +1,284 @@
+// Screenshots of the display, without any image library: PBM and PGM (the binary Netpbm
+// formats), and PNG with a minimal encoder that stores the pixels uncompressed.
+//
+// Each pixel of the display is a color index (see com::video), turned into a color by the
+// palette. PBM only has black and white, the dark colors of the palette becoming black.
+
+use std::io;
+use std::fs::File;
+use std::path::Path;
+
+use binary::{write_u16_le, write_u32};
+
+// Colors of the pixels, indexed by the planes they are lit on (same as the window).
+pub const DEFAULT_PALETTE: [[u8; 3]; 4] = [[0, 0, 0],
+                                           [255, 255, 255],
+                                           [170, 170, 170],
+                                           [85, 85, 85]];
+
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+// Largest block of data a stored deflate block can hold.
+const MAX_STORED_BLOCK: usize = 65535;
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub enum Format {
+    Pbm,
+    Pgm,
+    Png,
+}
+
+impl Format {
+    // The format matching the extension of the path.
+    pub fn from_path(path: &str) -> Option<Format> {
+        let extension = Path::new(path).extension().and_then(|ext| ext.to_str());
+        match extension.map(|ext| ext.to_lowercase()) {
+            Some(ref ext) if ext == "pbm" => Some(Format::Pbm),
+            Some(ref ext) if ext == "pgm" => Some(Format::Pgm),
+            Some(ref ext) if ext == "png" => Some(Format::Png),
+            _ => None,
+        }
+    }
+}
+
+// How the display is turned into an image.
+#[derive(Clone,Copy)]
+pub struct Settings {
+    // Size of a display pixel in the image, in image pixels
+    pub scale: usize,
+    pub palette: [[u8; 3]; 4],
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            scale: 1,
+            palette: DEFAULT_PALETTE,
+        }
+    }
+}
+
+// Parse a comma-separated list of up to 4 RRGGBB colors, the missing ones being left to their
+// default.
+pub fn parse_palette(colors: &str) -> Option<[[u8; 3]; 4]> {
+    let mut palette = DEFAULT_PALETTE;
+    let colors: Vec<&str> = colors.split(',').collect();
+    if colors.len() > palette.len() {
+        return None;
+    }
+    for (color, hex) in palette.iter_mut().zip(colors) {
+        let hex = hex.trim();
+        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        for (idx, component) in color.iter_mut().enumerate() {
+            *component = u8::from_str_radix(&hex[idx * 2..idx * 2 + 2], 16).ok()?;
+        }
+    }
+    Some(palette)
+}
+
+// Save the display in the format matching the extension of the path.
+pub fn save(path: &str, display: &[u8], width: usize, settings: &Settings) -> io::Result<()> {
+    let format = Format::from_path(path).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "unknown image format (pbm, pgm or png)")
+    })?;
+    let mut out = io::BufWriter::new(File::create(path)?);
+    write(&mut out, format, display, width, settings)
+}
+
+// Write the display, whose height follows from its width, as an image.
+pub fn write<W>(out: &mut W,
+                format: Format,
+                display: &[u8],
+                width: usize,
+                settings: &Settings)
+                -> io::Result<()>
+    where W: io::Write
+{
+    let scale = settings.scale.max(1);
+    let height = display.len() / width;
+    let (image_width, image_height) = (width * scale, height * scale);
+
+    // Scaled rows of color indexes
+    let rows = display.chunks(width).flat_map(|line| {
+        let row: Vec<u8> = line.iter()
+            .flat_map(|pixel| ::std::iter::repeat_n(*pixel, scale))
+            .collect();
+        ::std::iter::repeat_n(row, scale)
+    });
+
+    match format {
+        Format::Pbm => {
+            write!(out, "P4\n{} {}\n", image_width, image_height)?;
+            for row in rows {
+                // Eight pixels per byte, set for black
+                let bytes: Vec<u8> = row.chunks(8)
+                    .map(|pixels| {
+                        pixels.iter().enumerate().fold(0u8, |byte, (bit, pixel)| {
+                            let black = luma(&settings.palette[*pixel as usize & 3]) < 128;
+                            byte | (black as u8) << (7 - bit)
+                        })
+                    })
+                    .collect();
+                out.write_all(&bytes)?;
+            }
+            Ok(())
+        }
+        Format::Pgm => {
+            write!(out, "P5\n{} {}\n255\n", image_width, image_height)?;
+            let grays: Vec<u8> = settings.palette.iter().map(luma).collect();
+            for row in rows {
+                let bytes: Vec<u8> = row.iter().map(|pixel| grays[*pixel as usize & 3]).collect();
+                out.write_all(&bytes)?;
+            }
+            Ok(())
+        }
+        Format::Png => {
+            // Each row starts with its filter type, none
+            let mut pixels = Vec::with_capacity((image_width + 1) * image_height);
+            for row in rows {
+                pixels.push(0);
+                pixels.extend(row.iter().map(|pixel| pixel & 3));
+            }
+            write_png(out, image_width, image_height, &settings.palette, &pixels)
+        }
+    }
+}
+
+fn luma(color: &[u8; 3]) -> u8 {
+    ((color[0] as u32 * 299 + color[1] as u32 * 587 + color[2] as u32 * 114) / 1000) as u8
+}
+
+// 8-bit indexed PNG, from the filtered rows.
+fn write_png<W>(out: &mut W,
+                width: usize,
+                height: usize,
+                palette: &[[u8; 3]; 4],
+                rows: &[u8])
+                -> io::Result<()>
+    where W: io::Write
+{
+    out.write_all(PNG_SIGNATURE)?;
+
+    let mut header = Vec::new();
+    write_u32(&mut header, width as u32)?;
+    write_u32(&mut header, height as u32)?;
+    // Bit depth, color type (indexed), compression, filter and interlace methods
+    header.extend_from_slice(&[8, 3, 0, 0, 0]);
+    write_chunk(out, b"IHDR", &header)?;
+
+    let colors: Vec<u8> = palette.iter().flat_map(|color| color.iter().cloned()).collect();
+    write_chunk(out, b"PLTE", &colors)?;
+
+    // zlib stream of stored deflate blocks: no compression, the images are small anyway
+    let mut data = vec![0x78, 0x01];
+    let mut blocks = rows.chunks(MAX_STORED_BLOCK).peekable();
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        data.push(last as u8);
+        write_u16_le(&mut data, block.len() as u16)?;
+        write_u16_le(&mut data, !(block.len() as u16))?;
+        data.extend_from_slice(block);
+    }
+    write_u32(&mut data, adler32(rows))?;
+    write_chunk(out, b"IDAT", &data)?;
+
+    write_chunk(out, b"IEND", &[])
+}
+
+fn write_chunk<W>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()>
+    where W: io::Write
+{
+    write_u32(out, data.len() as u32)?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    write_u32(out, !crc32(crc32(!0, kind), data))
+}
+
+// CRC-32 (ISO-HDLC) update, the caller doing the initial and final inversions.
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), byte| {
+        let a = (a + *byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DEFAULT_PALETTE, Format, Settings, adler32, crc32, parse_palette, write};
+
+    fn image(format: Format, display: &[u8], width: usize, scale: usize) -> Vec<u8> {
+        let settings = Settings { scale, ..Settings::default() };
+        let mut out = Vec::new();
+        write(&mut out, format, display, width, &settings).unwrap();
+        out
+    }
+
+    #[test]
+    fn pbm() {
+        // The dark colors (0 and 3) are black, rows are padded to a whole byte
+        let display = [1, 0, 1, 1, 1, 1, 1, 1, 1, 0, 3, 1, 1, 1, 1, 1, 1, 1, 2, 1];
+        let mut expected = b"P4\n10 2\n".to_vec();
+        expected.extend_from_slice(&[0x40, 0x40, 0x80, 0x00]);
+        assert_eq!(image(Format::Pbm, &display, 10, 1), expected);
+
+        let mut expected = b"P4\n6 6\n".to_vec();
+        expected.extend_from_slice(&[0x30, 0x30, 0xCC, 0xCC, 0xFC, 0xFC]);
+        assert_eq!(image(Format::Pbm, &[1, 0, 1, 0, 1, 3, 3, 3, 0], 3, 2), expected);
+    }
+
+    #[test]
+    fn pgm() {
+        let mut expected = b"P5\n2 2\n255\n".to_vec();
+        expected.extend_from_slice(&[0, 255, 170, 85]);
+        assert_eq!(image(Format::Pgm, &[0, 1, 2, 3], 2, 1), expected);
+    }
+
+    #[test]
+    fn png() {
+        let png = image(Format::Png, &[1, 2], 2, 1);
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        // IHDR: 2x1, 8-bit indexed
+        assert_eq!(&png[8..16], b"\0\0\0\x0dIHDR");
+        assert_eq!(&png[16..29], &[0, 0, 0, 2, 0, 0, 0, 1, 8, 3, 0, 0, 0]);
+        // PLTE
+        assert_eq!(&png[33..41], b"\0\0\0\x0cPLTE");
+        // IDAT: a single stored block with the filter byte and the indexes, then the Adler-32
+        let idat = 41 + 12 + 12;
+        assert_eq!(&png[idat - 8..idat], b"\0\0\0\x0eIDAT");
+        assert_eq!(&png[idat..idat + 14],
+                   &[0x78, 0x01, 0x01, 0x03, 0x00, 0xFC, 0xFF, 0, 1, 2, 0x00, 0x07, 0x00, 0x04]);
+        assert_eq!(&png[png.len() - 12..], b"\0\0\0\0IEND\xae\x42\x60\x82");
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(!crc32(!0, b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn palette() {
+        let mut expected = DEFAULT_PALETTE;
+        expected[0] = [0xFF, 0x00, 0x00];
+        expected[1] = [0x00, 0xAB, 0xCD];
+        assert_eq!(parse_palette("ff0000, 00abCD"), Some(expected));
+
+        assert_eq!(parse_palette("000000,111111,222222,333333,444444"), None);
+        assert_eq!(parse_palette("00000g"), None);
+        assert_eq!(parse_palette("000000,"), None);
+        assert_eq!(parse_palette("fff"), None);
+    }
+}
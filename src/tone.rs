@@ -0,0 +1,130 @@
+// Sound generation: the samples played while the sound timer runs, computed from the audio
+// state of the machine. Generators don't depend on the audio output, so they can be run
+// without it and their samples inspected.
+
+use com::audio::AudioCommunicator;
+
+// Pitch of the beeper, in Hz.
+pub const DEFAULT_FREQUENCY: u32 = 440;
+
+// Volume of the beeper, in percent.
+pub const DEFAULT_VOLUME: u8 = 25;
+
+pub trait ToneGenerator: Send {
+    // Follow the audio state of the machine, for the next samples.
+    fn update(&mut self, audio: &AudioCommunicator);
+
+    // Fill the buffer with the next samples, mono signed 16-bit.
+    fn generate(&mut self, samples: &mut [i16]);
+}
+
+// Square wave played while beeping, silence otherwise.
+pub struct SquareWave {
+    sample_rate: u32,
+    frequency: u32,
+    amplitude: i16,
+    beeping: bool,
+    // Position in the current period, a period being 2 * sample_rate
+    phase: u32,
+}
+
+impl SquareWave {
+    // The frequency is clamped to what the sample rate can play (half of it).
+    pub fn new(sample_rate: u32, frequency: u32, volume: u8) -> SquareWave {
+        let mut wave = SquareWave {
+            sample_rate,
+            frequency: frequency.max(1).min(sample_rate / 2),
+            amplitude: 0,
+            beeping: false,
+            phase: 0,
+        };
+        wave.set_volume(volume);
+        wave
+    }
+
+    // Volume in percent, 100 being the loudest.
+    pub fn set_volume(&mut self, volume: u8) {
+        self.amplitude = (i16::MAX as u32 * volume.min(100) as u32 / 100) as i16;
+    }
+}
+
+impl ToneGenerator for SquareWave {
+    fn update(&mut self, audio: &AudioCommunicator) {
+        // Each beep starts at the beginning of a period
+        if audio.beeping && !self.beeping {
+            self.phase = 0;
+        }
+        self.beeping = audio.beeping;
+    }
+
+    fn generate(&mut self, samples: &mut [i16]) {
+        if !self.beeping {
+            for sample in samples.iter_mut() {
+                *sample = 0;
+            }
+            return;
+        }
+        for sample in samples.iter_mut() {
+            *sample = if self.phase < self.sample_rate { self.amplitude } else { -self.amplitude };
+            self.phase = (self.phase + 2 * self.frequency) % (2 * self.sample_rate);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SquareWave, ToneGenerator};
+    use com::audio::AudioCommunicator;
+
+    fn audio(beeping: bool) -> AudioCommunicator {
+        let mut audio = AudioCommunicator::new();
+        audio.beeping = beeping;
+        audio
+    }
+
+    fn generate(wave: &mut SquareWave, count: usize) -> Vec<i16> {
+        let mut samples = vec![1i16; count];
+        wave.generate(&mut samples);
+        samples
+    }
+
+    #[test]
+    fn silent_when_not_beeping() {
+        let mut wave = SquareWave::new(44100, 440, 100);
+        assert!(generate(&mut wave, 1000).iter().all(|sample| *sample == 0));
+        wave.update(&audio(true));
+        wave.update(&audio(false));
+        assert!(generate(&mut wave, 1000).iter().all(|sample| *sample == 0));
+    }
+
+    #[test]
+    fn period_and_amplitude() {
+        // 8 samples per period
+        let mut wave = SquareWave::new(8000, 1000, 50);
+        wave.update(&audio(true));
+        let high = 16383;
+        let period = [high, high, high, high, -high, -high, -high, -high];
+        assert_eq!(generate(&mut wave, 16), [period, period].concat());
+
+        wave.set_volume(100);
+        assert_eq!(generate(&mut wave, 1), [i16::MAX]);
+        wave.set_volume(200);
+        assert_eq!(generate(&mut wave, 1), [i16::MAX]);
+    }
+
+    #[test]
+    fn beeps_start_at_the_beginning_of_a_period() {
+        let mut wave = SquareWave::new(8000, 1000, 100);
+        let high = i16::MAX;
+        wave.update(&audio(true));
+        assert_eq!(generate(&mut wave, 6), [high, high, high, high, -high, -high]);
+        // Still the same beep
+        wave.update(&audio(true));
+        assert_eq!(generate(&mut wave, 3), [-high, -high, high]);
+
+        wave.update(&audio(false));
+        generate(&mut wave, 3);
+        wave.update(&audio(true));
+        assert_eq!(generate(&mut wave, 5), [high, high, high, high, -high]);
+    }
+}
@@ -1,5 +1,7 @@
 use sdl2::EventPump;
 use sdl2::event::Event;
+use sdl2::keyboard::Scancode;
+use com::input::InputCommunicator;
 
 /*
 pub enum KeyEvent {
@@ -10,14 +12,35 @@ pub enum KeyEvent {
 }
 */
 
+// Emulator commands bound to keys outside of the keypad.
+#[derive(Clone,Copy)]
+pub enum Hotkey {
+    SaveState,
+    LoadState,
+    Rewind,
+    SlowMotion,
+    Mute,
+    Screenshot,
+    RecordGif,
+}
+
+impl Hotkey {
+    // Whether holding the key down repeats the command.
+    fn repeats(&self) -> bool {
+        matches!(*self, Hotkey::Rewind)
+    }
+}
+
 pub struct ImmediateEvents {
     pub repaint: Option<Event>,
+    pub hotkeys: Vec<Hotkey>,
 }
 
 impl ImmediateEvents {
     pub fn new() -> ImmediateEvents {
         ImmediateEvents {
             repaint: None,
+            hotkeys: Vec::new(),
         }
     }
 }
@@ -26,6 +49,10 @@ pub struct Events {
     pump: EventPump,
     pub immediate: ImmediateEvents,
     pub quit: bool,
+    // Fast-forward, while its key is held down
+    pub turbo: bool,
+    // Same for rewinding, the rewind hotkey repeating meanwhile
+    pub rewinding: bool,
 }
 
 impl Events {
@@ -34,17 +61,80 @@ impl Events {
             pump: pump,
             immediate: ImmediateEvents::new(),
             quit: false,
+            turbo: false,
+            rewinding: false,
         }
     }
 
-    pub fn poll(&mut self) {
+    pub fn poll(&mut self, input: &mut InputCommunicator) {
         self.immediate = ImmediateEvents::new();
         for event in self.pump.poll_iter() {
             match event {
                 Event::Quit { .. } => self.quit = true,
                 Event::Window { .. } => self.immediate.repaint = Some(event),
+                Event::KeyDown { scancode: Some(Scancode::Tab), .. } => self.turbo = true,
+                Event::KeyUp { scancode: Some(Scancode::Tab), .. } => self.turbo = false,
+                Event::KeyDown { scancode: Some(scancode), repeat, .. } => {
+                    if let Some(key) = map_key(scancode) {
+                        input.press(key);
+                    } else if let Some(hotkey) = map_hotkey(scancode) {
+                        if let Hotkey::Rewind = hotkey {
+                            self.rewinding = true;
+                        }
+                        if !repeat || hotkey.repeats() {
+                            self.immediate.hotkeys.push(hotkey);
+                        }
+                    }
+                }
+                Event::KeyUp { scancode: Some(scancode), .. } => {
+                    if let Some(key) = map_key(scancode) {
+                        input.release(key);
+                    } else if let Some(Hotkey::Rewind) = map_hotkey(scancode) {
+                        self.rewinding = false;
+                    }
+                }
                 _ => (),
             }
         }
     }
-}
\ No newline at end of file
+}
+
+// Map the left side of the keyboard to the hex keypad, by key position:
+//   1 2 3 4        1 2 3 C
+//   Q W E R   =>   4 5 6 D
+//   A S D F        7 8 9 E
+//   Z X C V        A 0 B F
+fn map_key(scancode: Scancode) -> Option<u8> {
+    match scancode {
+        Scancode::Num1 => Some(0x1),
+        Scancode::Num2 => Some(0x2),
+        Scancode::Num3 => Some(0x3),
+        Scancode::Num4 => Some(0xC),
+        Scancode::Q => Some(0x4),
+        Scancode::W => Some(0x5),
+        Scancode::E => Some(0x6),
+        Scancode::R => Some(0xD),
+        Scancode::A => Some(0x7),
+        Scancode::S => Some(0x8),
+        Scancode::D => Some(0x9),
+        Scancode::F => Some(0xE),
+        Scancode::Z => Some(0xA),
+        Scancode::X => Some(0x0),
+        Scancode::C => Some(0xB),
+        Scancode::V => Some(0xF),
+        _ => None,
+    }
+}
+
+fn map_hotkey(scancode: Scancode) -> Option<Hotkey> {
+    match scancode {
+        Scancode::F5 => Some(Hotkey::SaveState),
+        Scancode::F9 => Some(Hotkey::LoadState),
+        Scancode::Backspace => Some(Hotkey::Rewind),
+        Scancode::F2 => Some(Hotkey::SlowMotion),
+        Scancode::F3 => Some(Hotkey::Mute),
+        Scancode::F11 => Some(Hotkey::RecordGif),
+        Scancode::F12 => Some(Hotkey::Screenshot),
+        _ => None,
+    }
+}
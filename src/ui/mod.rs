@@ -1,4 +1,5 @@
 mod events;
+mod sound;
 
 use std::error::Error;
 use sdl2;
@@ -8,14 +9,28 @@ use sdl2::render::Renderer;
 use com::Communicator;
 use com::video::{VideoCommunicator, VideoSignal};
 use self::events::Events;
+use self::sound::Sound;
+use Options;
+pub use self::events::Hotkey;
+
+// Colors of the pixels, indexed by the planes they are lit on
+const PALETTE: [Color; 4] = [Color::RGB(0, 0, 0),
+                             Color::RGB(255, 255, 255),
+                             Color::RGB(170, 170, 170),
+                             Color::RGB(85, 85, 85)];
 
 pub struct UiContext<'window> {
     pub renderer: Renderer<'window>,
     pub events: Events,
+    resolution: (usize, usize),
+    // None if there's no audio device
+    sound: Option<Sound>,
 }
 
 impl<'window> UiContext<'window> {
-    pub fn new(title: &'static str) -> Result<UiContext, Box<Error>> {
+    pub fn new(title: &'static str,
+               options: &Options)
+               -> Result<UiContext<'static>, Box<dyn Error>> {
         let sdl_context = sdl2::init()?;
         let video = sdl_context.video()?;
         let window = video
@@ -24,27 +39,34 @@ impl<'window> UiContext<'window> {
             .resizable()
             .build()?;
 
+        // The emulator is still usable without sound
+        let sound = match Sound::open(&sdl_context, options.tone_frequency, options.volume) {
+            Ok(sound) => Some(sound),
+            Err(e) => {
+                warn!("Can't open the audio device: {}.", e);
+                None
+            }
+        };
+
         Ok(UiContext {
                renderer: window.renderer().accelerated().build()?,
                events: Events::new(sdl_context.event_pump()?),
+               resolution: (0, 0),
+               sound,
            })
     }
 
     pub fn update(&mut self, com: &mut Communicator) {
-        self.events.poll();
+        self.events.poll(&mut com.input);
+        if let Some(ref mut sound) = self.sound {
+            sound.update(&com.audio);
+        }
 
-        // TODO: Do something with scale here too.
-        if let Some(_) = self.events.immediate.repaint {
-            let size = self.renderer
-                .window()
-                .map(|window| (window.size().0 as f32, window.size().1 as f32));
-            if let Some(size) = size {
-                if let Err(e) = self.renderer
-                       .set_scale(size.0 / com.video.width as f32,
-                                  size.1 / com.video.height as f32) {
-                    warn!("Can't set renderer scale: {}.", e);
-                }
-            }
+        // The scale needs to follow both the window size and the emulated display resolution
+        let resolution = (com.video.width, com.video.height);
+        if self.events.immediate.repaint.is_some() || resolution != self.resolution {
+            self.resolution = resolution;
+            self.update_scale();
             self.render(&mut com.video);
         }
 
@@ -56,24 +78,53 @@ impl<'window> UiContext<'window> {
         com.video.signal = VideoSignal::None;
     }
 
+    pub fn toggle_mute(&mut self) {
+        match self.sound {
+            Some(ref mut sound) => {
+                if sound.toggle_mute() {
+                    info!("Sound muted.");
+                } else {
+                    info!("Sound unmuted.");
+                }
+            }
+            None => warn!("There's no sound to mute."),
+        }
+    }
+
+    fn update_scale(&mut self) {
+        let size = self.renderer
+            .window()
+            .map(|window| (window.size().0 as f32, window.size().1 as f32));
+        if let Some(size) = size {
+            if let Err(e) = self.renderer
+                   .set_scale(size.0 / self.resolution.0 as f32,
+                              size.1 / self.resolution.1 as f32) {
+                warn!("Can't set renderer scale: {}.", e);
+            }
+        }
+    }
+
     fn clear(&mut self) {
         self.renderer.clear();
         self.renderer.present();
     }
 
     fn render(&mut self, video_com: &mut VideoCommunicator) {
+        self.renderer.set_draw_color(PALETTE[0]);
         self.renderer.clear();
-        self.renderer.set_draw_color(Color::RGB(255, 255, 255));
-        for (idx, pixel) in video_com.display.iter().enumerate() {
-            if *pixel {
-                if let Err(e) = self.renderer
-                       .draw_point(Point::new((idx % video_com.width) as i32,
-                                              (idx / video_com.width) as i32)) {
-                    warn!("Can't draw point with renderer: {}.", e);
+        for (color_idx, color) in PALETTE.iter().enumerate().skip(1) {
+            self.renderer.set_draw_color(*color);
+            for (idx, pixel) in video_com.display.iter().enumerate() {
+                if *pixel as usize == color_idx {
+                    if let Err(e) = self.renderer
+                           .draw_point(Point::new((idx % video_com.width) as i32,
+                                                  (idx / video_com.width) as i32)) {
+                        warn!("Can't draw point with renderer: {}.", e);
+                    }
                 }
             }
         }
-        self.renderer.set_draw_color(Color::RGB(0, 0, 0));
+        self.renderer.set_draw_color(PALETTE[0]);
         self.renderer.present();
     }
-}
\ No newline at end of file
+}
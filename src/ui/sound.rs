@@ -0,0 +1,62 @@
+use sdl2::Sdl;
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use com::audio::AudioCommunicator;
+use tone::{SquareWave, ToneGenerator};
+
+const SAMPLE_RATE: i32 = 44100;
+
+// Called by SDL from its audio thread whenever it needs samples.
+struct Playback {
+    tone: Box<dyn ToneGenerator>,
+    muted: bool,
+}
+
+impl AudioCallback for Playback {
+    type Channel = i16;
+
+    fn callback(&mut self, out: &mut [i16]) {
+        // The tone keeps going while muted, so that unmuting doesn't restart it
+        self.tone.generate(out);
+        if self.muted {
+            for sample in out.iter_mut() {
+                *sample = 0;
+            }
+        }
+    }
+}
+
+pub struct Sound {
+    device: AudioDevice<Playback>,
+}
+
+impl Sound {
+    // Open the default audio device, playing a square wave of the given frequency and volume.
+    pub fn open(sdl_context: &Sdl, frequency: u32, volume: u8) -> Result<Sound, String> {
+        let audio = sdl_context.audio()?;
+        let desired = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE),
+            channels: Some(1),
+            samples: None,
+        };
+        let device = audio
+            .open_playback(None, &desired, |spec| {
+                Playback {
+                    tone: Box::new(SquareWave::new(spec.freq as u32, frequency, volume)),
+                    muted: false,
+                }
+            })?;
+        device.resume();
+        Ok(Sound { device })
+    }
+
+    pub fn update(&mut self, audio: &AudioCommunicator) {
+        self.device.lock().tone.update(audio);
+    }
+
+    // Returns whether the sound is now muted.
+    pub fn toggle_mute(&mut self) -> bool {
+        let mut playback = self.device.lock();
+        playback.muted = !playback.muted;
+        playback.muted
+    }
+}
@@ -0,0 +1,155 @@
+// Recording of the sound output to a WAV file: mono 16-bit PCM at a fixed sample rate. The
+// samples follow emulated time (a frame is always SAMPLES_PER_FRAME samples), not the host, so
+// a run always gives the same file for the same input.
+
+use std::io::{self, Seek, SeekFrom};
+
+use cpu::CPU;
+use com::audio::AudioCommunicator;
+use tone::{SquareWave, ToneGenerator};
+use binary::{write_u16_le, write_u32_le};
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+// Samples in a 60 Hz frame.
+pub const SAMPLES_PER_FRAME: u64 = 735;
+
+const HEADER_SIZE: u32 = 44;
+const BYTES_PER_SAMPLE: u32 = 2;
+
+pub struct AudioRecorder<W>
+    where W: io::Write + Seek
+{
+    out: W,
+    tone: Box<dyn ToneGenerator>,
+    // Samples written so far
+    samples: u64,
+}
+
+impl<W> AudioRecorder<W>
+    where W: io::Write + Seek
+{
+    // Start a recording of the beeper with the given frequency and volume.
+    pub fn new(out: W, frequency: u32, volume: u8) -> io::Result<AudioRecorder<W>> {
+        let tone = SquareWave::new(SAMPLE_RATE, frequency, volume);
+        AudioRecorder::with_tone(out, Box::new(tone))
+    }
+
+    pub fn with_tone(mut out: W, tone: Box<dyn ToneGenerator>) -> io::Result<AudioRecorder<W>> {
+        // The sizes are only known at the end
+        write_header(&mut out, 0)?;
+        Ok(AudioRecorder {
+               out,
+               tone,
+               samples: 0,
+           })
+    }
+
+    // Call after each step, to write the samples up to the current emulated time. Time going
+    // back (rewinding, loading a state) writes nothing until it catches up.
+    pub fn record(&mut self, cpu: &CPU, audio: &AudioCommunicator) -> io::Result<()> {
+        let time = cpu.frames() * SAMPLES_PER_FRAME + cpu.frame_position(SAMPLES_PER_FRAME);
+        if time <= self.samples {
+            return Ok(());
+        }
+
+        self.tone.update(audio);
+        let mut samples = vec![0i16; (time - self.samples) as usize];
+        self.tone.generate(&mut samples);
+        for sample in samples.iter() {
+            write_u16_le(&mut self.out, *sample as u16)?;
+        }
+        self.samples = time;
+        Ok(())
+    }
+
+    // Samples written so far.
+    pub fn len(&self) -> u64 {
+        self.samples
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples == 0
+    }
+
+    // Write the final sizes in the header, returning the output.
+    pub fn finish(mut self) -> io::Result<W> {
+        let data_size = self.samples * BYTES_PER_SAMPLE as u64;
+        if data_size > (u32::MAX - HEADER_SIZE) as u64 {
+            return Err(io::Error::other("the recording is too long for a WAV file"));
+        }
+        self.out.seek(SeekFrom::Start(0))?;
+        write_header(&mut self.out, data_size as u32)?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn write_header<W>(out: &mut W, data_size: u32) -> io::Result<()>
+    where W: io::Write
+{
+    out.write_all(b"RIFF")?;
+    write_u32_le(out, HEADER_SIZE - 8 + data_size)?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    write_u32_le(out, 16)?;
+    // PCM, mono
+    write_u16_le(out, 1)?;
+    write_u16_le(out, 1)?;
+    write_u32_le(out, SAMPLE_RATE)?;
+    write_u32_le(out, SAMPLE_RATE * BYTES_PER_SAMPLE)?;
+    write_u16_le(out, BYTES_PER_SAMPLE as u16)?;
+    write_u16_le(out, BYTES_PER_SAMPLE as u16 * 8)?;
+
+    out.write_all(b"data")?;
+    write_u32_le(out, data_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::AudioRecorder;
+    use cpu::CPU;
+    use com::Communicator;
+
+    // Beeps for longer and longer, once a second
+    const ROM: [u8; 18] = [0x60, 0x05, 0x62, 0x3C, 0xF0, 0x18, 0xF2, 0x15, 0xF1, 0x07, 0x31,
+                           0x00, 0x12, 0x08, 0x70, 0x07, 0x12, 0x04];
+
+    fn record() -> Vec<u8> {
+        let mut cpu = CPU::new();
+        cpu.load_rom(ROM.to_vec());
+        let mut com = Communicator::new();
+        let mut recorder = AudioRecorder::new(Cursor::new(Vec::new()), 440, 50).unwrap();
+        while cpu.frames() < 400 {
+            cpu.step(&mut com).unwrap();
+            recorder.record(&cpu, &com.audio).unwrap();
+        }
+        recorder.finish().unwrap().into_inner()
+    }
+
+    fn u32_at(data: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+    }
+
+    #[test]
+    fn identical_across_runs() {
+        let first = record();
+        assert_eq!(first, record());
+
+        assert_eq!(&first[..4], b"RIFF");
+        assert_eq!(u32_at(&first, 4) as usize, first.len() - 8);
+        assert_eq!(&first[36..40], b"data");
+        assert_eq!(u32_at(&first, 40) as usize, first.len() - 44);
+        assert_eq!(first.len() - 44, 400 * 735 * 2);
+
+        let samples = &first[44..];
+        // Beeping for the first 5 frames, then silent until the next second
+        let frame = |n: usize| n * 735 * 2;
+        assert!(samples[..frame(4)].iter().any(|byte| *byte != 0));
+        assert!(samples[frame(10)..frame(50)].iter().all(|byte| *byte == 0));
+    }
+}